@@ -0,0 +1,32 @@
+/// Function to compute the checksum of a complete message (header and payload).
+/// The checksum field itself (bytes 4..6) is treated as 0xCCCC while summing.
+pub fn compute_checksum(message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // Iterate over each 2-byte word in the message.
+    let mut message_index = 0;
+    while message_index < message.len() {
+        let word = if message_index == 4 { // The 2 bytes contained in the checksum field.
+            0xCCCCu16
+        } else {
+            let high_byte = message[message_index] as u16;
+            let low_byte = if message_index + 1 < message.len() { message[message_index + 1] as u16 } else { 0 };
+            (high_byte << 8) | low_byte
+        };
+
+        sum += word as u32; // Store sum as 32 bit to avoid any overflow.
+
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        message_index += 2;
+    }
+
+    !(sum as u16) // Finds the one's complement of the calculated sum.
+}
+
+/// Function to verify the stated checksum against the given message and return an appropriate true/false value.
+pub fn verify_checksum(message: &[u8], checksum: u16) -> bool {
+    compute_checksum(message) == checksum
+}
@@ -0,0 +1,385 @@
+use crate::checksum::compute_checksum;
+use crate::error::{FrameError, Validation};
+use crate::header::{CtmpHeader, HEADER_LEN, MAGIC};
+use crate::message::CtmpMessage;
+
+/// Number of bytes of checksum verification earned by every byte pushed into the decoder. See [`Decoder::next_frame`].
+const VERIFY_CREDIT: usize = 32;
+
+/// Incremental decoder turning a stream of bytes into CTMP messages.
+///
+/// Bytes are added with [`Decoder::push`] as they arrive, and complete frames are taken out with
+/// [`Decoder::next_frame`]. Anything before a magic byte is discarded. Frames failing validation are dropped and
+/// returned as a [`FrameError`] describing why, after which the decoder resynchronises on the next magic byte.
+///
+/// Neither validation mode can tell a header found by chance in garbage from a real one. A stray magic byte followed by
+/// bytes that pass validation makes the decoder commit to the length they state: it waits for that many bytes, and if
+/// the header is not sensitive returns them as one bogus message, swallowing any real frames among them. Strict
+/// validation makes this much rarer, as a header found by chance is then very unlikely to pass validation.
+///
+/// The work spent verifying the checksums of bogus frames is bounded by the number of bytes received, so a stream of
+/// garbage cannot make the decoder do an amount of work that grows faster than the stream.
+#[derive(Debug)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// Offset in `buffer` of the first byte not yet decoded. Bytes before it are only removed when the buffer is
+    /// compacted, so skipping a byte does not move the rest of the buffer.
+    start: usize,
+    max_payload: usize,
+    max_buffer: usize,
+    validation: Validation,
+    discarded: u64,
+    /// Number of bytes that may still be hashed verifying checksums that turn out to be wrong.
+    verify_budget: usize,
+    /// Total number of bytes hashed verifying checksums, so tests can check the work done.
+    #[cfg(test)]
+    hashed: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        let max_payload = u16::MAX as usize;
+        Decoder {
+            buffer: Vec::new(),
+            start: 0,
+            max_payload,
+            max_buffer: HEADER_LEN + max_payload,
+            validation: Validation::Lenient,
+            discarded: 0,
+            verify_budget: 0,
+            #[cfg(test)]
+            hashed: 0,
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Sets the maximum accepted payload length. Headers stating a longer payload are rejected.
+    pub fn with_max_payload(mut self, max_payload: usize) -> Decoder {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// Sets the maximum number of bytes held in the buffer. The limit is raised if needed to fit one frame with the
+    /// maximum payload length, as otherwise such a frame could never be decoded.
+    pub fn with_max_buffer(mut self, max_buffer: usize) -> Decoder {
+        self.max_buffer = max_buffer;
+        self
+    }
+
+    /// Sets how strictly header fields are checked. Defaults to lenient.
+    pub fn with_validation(mut self, validation: Validation) -> Decoder {
+        self.validation = validation;
+        self
+    }
+
+    /// Appends newly received bytes to the decoder's buffer.
+    /// At most [`Decoder::space`] bytes are accepted; the number of bytes accepted is returned and the rest are
+    /// discarded, so the buffer never grows past its limit.
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let accepted = bytes.len().min(self.space());
+        // Decoded bytes are removed once they make up half of the buffer, so each byte is moved a bounded number of
+        // times and the buffer never holds more than twice its limit.
+        if self.start > 0 && self.start * 2 >= self.buffer.len() {
+            self.buffer.drain(..self.start);
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(&bytes[..accepted]);
+        // The budget is capped, so a long run of valid frames does not pay for an equally long run of garbage.
+        self.verify_budget = (self.verify_budget + accepted * VERIFY_CREDIT).min(self.buffer_limit() * VERIFY_CREDIT);
+        accepted
+    }
+
+    /// Number of bytes that can be pushed before the buffer reaches its limit.
+    /// Once every complete frame has been taken out with [`Decoder::next_frame`], this is never zero.
+    pub fn space(&self) -> usize {
+        self.buffer_limit() - self.buffered()
+    }
+
+    /// Maximum number of bytes held in the buffer, which always fits a frame with the maximum payload length.
+    fn buffer_limit(&self) -> usize {
+        self.max_buffer.max(HEADER_LEN + self.max_payload)
+    }
+
+    /// Number of bytes currently held in the buffer waiting to be decoded.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Returns the number of garbage bytes discarded before a magic byte since the last call, and resets the count.
+    pub fn take_discarded(&mut self) -> u64 {
+        std::mem::take(&mut self.discarded)
+    }
+
+    /// Returns the next complete frame in the buffer, or `None` if more bytes are needed.
+    ///
+    /// A magic byte may also appear by chance in garbage or inside a payload, so a candidate header is validated before
+    /// the decoder commits to the length it states, and a sensitive frame's checksum is verified before it is removed.
+    /// If either check fails only the candidate's magic byte is skipped and the error returned, so that any real frame
+    /// starting inside the bogus frame is still found on the next call.
+    ///
+    /// Rescanning a bogus sensitive frame from the next byte means hashing it again for every magic byte inside it, so
+    /// e.g. a run of `0xCC` bytes, which parses as sensitive frames of 52 KB, would take work growing with the square
+    /// of its length. Each byte pushed therefore pays for hashing a fixed number of bytes of wrong checksums. Once
+    /// that budget is used up, a frame with a wrong checksum is skipped whole, losing any real frame starting inside
+    /// it, until enough new bytes have been pushed.
+    pub fn next_frame(&mut self) -> Option<Result<CtmpMessage, FrameError>> {
+        // Look for magic byte.
+        let Some(pos) = self.buffer[self.start..].iter().position(|&b| b == MAGIC) else {
+            // If there is no magic byte found, discard everything in the buffer.
+            self.discarded += self.buffered() as u64;
+            self.buffer.clear();
+            self.start = 0;
+            return None;
+        };
+
+        // Discard anything before the magic byte.
+        self.discarded += pos as u64;
+        self.start += pos;
+
+        // Message is not long enough to have the full header yet.
+        let header = CtmpHeader::parse(&self.buffer[self.start..])?;
+
+        if let Err(e) = self.check_header(&header) { // Not a valid header, resynchronise from the next byte.
+            self.start += 1;
+            return Some(Err(e));
+        }
+
+        if self.buffered() < header.frame_len() { // Full message has not been received yet.
+            return None;
+        }
+
+        let frame_len = header.frame_len();
+        let frame = &self.buffer[self.start..self.start + frame_len];
+        if header.is_sensitive() {
+            let computed = compute_checksum(frame);
+            #[cfg(test)]
+            {
+                self.hashed += frame_len;
+            }
+            if computed != header.checksum { // Checksum is not correct, resynchronise from the next byte if the budget allows.
+                let skip = if self.verify_budget >= frame_len { 1 } else { frame_len };
+                self.verify_budget = self.verify_budget.saturating_sub(frame_len);
+                self.start += skip;
+                return Some(Err(FrameError::ChecksumMismatch { stated: header.checksum, computed }));
+            }
+        }
+
+        let bytes = frame.to_vec();
+        self.start += frame_len;
+        CtmpMessage::from_bytes(bytes).map(Ok)
+    }
+
+    /// Checks a candidate header against the maximum payload length and the validation mode.
+    fn check_header(&self, header: &CtmpHeader) -> Result<(), FrameError> {
+        if header.length as usize > self.max_payload {
+            return Err(FrameError::PayloadTooLarge { length: header.length, max: self.max_payload });
+        }
+        header.validate(self.validation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{Arbitrary, Gen, quickcheck};
+
+    use super::*;
+    use crate::encoder::Encoder;
+    use crate::header::{OPTION_SENSITIVE, OPTIONS_RESERVED, Version};
+
+    /// A message to encode, followed in the stream by noise.
+    #[derive(Debug, Clone)]
+    struct Part {
+        payload: Vec<u8>,
+        sensitive: bool,
+        version: Version,
+        message_type: u8,
+        /// Sent with a wrong checksum, so it must be dropped.
+        corrupt: bool,
+        noise: Vec<u8>,
+    }
+
+    impl Arbitrary for Part {
+        fn arbitrary(g: &mut Gen) -> Part {
+            let sensitive = bool::arbitrary(g);
+            Part {
+                payload: Vec::arbitrary(g),
+                sensitive,
+                version: *g.choose(&Version::ALL).unwrap(),
+                message_type: u8::arbitrary(g),
+                corrupt: sensitive && *g.choose(&[false, false, true]).unwrap(),
+                noise: Vec::arbitrary(g),
+            }
+        }
+    }
+
+    impl Part {
+        /// Function to encode the message, with its checksum broken if it is corrupt.
+        fn frame(&self) -> Vec<u8> {
+            let options = if self.sensitive { OPTION_SENSITIVE } else { 0 };
+            let mut frame = Encoder::new().options(options).version(self.version).message_type(self.message_type).encode(&self.payload).unwrap().into_bytes();
+            if self.corrupt {
+                frame[4] ^= 0x01;
+            }
+            frame
+        }
+    }
+
+    /// Function to build a stream of the messages interleaved with noise, returning it and the valid messages with
+    /// their offsets in the stream.
+    ///
+    /// If a validation mode is given, the noise is made so that it never forms a header the decoder accepts, as it
+    /// would then commit to the stated length and swallow the messages after it: under lenient validation it holds no
+    /// magic bytes, and under strict validation every magic byte is followed by reserved option bits. For the same
+    /// reason, corrupt messages are then only kept if no magic byte follows their first.
+    fn build_stream(parts: &[Part], safe_for: Option<Validation>) -> (Vec<u8>, Vec<(usize, Vec<u8>)>) {
+        let mut stream = Vec::new();
+        let mut expected = Vec::new();
+        for part in parts {
+            let frame = part.frame();
+            if safe_for.is_some() && part.corrupt && frame[1..].contains(&MAGIC) {
+                continue;
+            }
+            if !part.corrupt {
+                expected.push((stream.len(), frame.clone()));
+            }
+            stream.extend_from_slice(&frame);
+
+            for &byte in &part.noise {
+                let byte = match safe_for {
+                    Some(Validation::Lenient) if byte == MAGIC => 0,
+                    Some(Validation::Strict) if stream.last() == Some(&MAGIC) && byte & OPTIONS_RESERVED == 0 => byte | 0x01,
+                    _ => byte,
+                };
+                stream.push(byte);
+            }
+        }
+        (stream, expected)
+    }
+
+    /// What a decoder made of a stream.
+    struct Decoded {
+        /// Messages returned, with their offsets in the stream.
+        frames: Vec<(usize, Vec<u8>)>,
+        /// Ranges of the stream the decoder committed to as one candidate frame: those returned as messages or errors,
+        /// and the incomplete candidate still buffered at the end.
+        committed: Vec<(usize, usize)>,
+    }
+
+    impl Decoded {
+        fn messages(&self) -> Vec<Vec<u8>> {
+            self.frames.iter().map(|(_, frame)| frame.clone()).collect()
+        }
+    }
+
+    /// Function to push a stream into a decoder in chunks of the given sizes, returning what it decoded.
+    fn decode(decoder: &mut Decoder, stream: &[u8], chunks: &[u8]) -> Decoded {
+        let mut decoded = Decoded { frames: Vec::new(), committed: Vec::new() };
+        let mut chunks = chunks.iter().map(|&len| len.max(1) as usize).cycle();
+        let mut pushed = 0;
+        while pushed < stream.len() {
+            let len = chunks.next().unwrap_or(stream.len()).min(stream.len() - pushed);
+            pushed += decoder.push(&stream[pushed..pushed + len]);
+            loop {
+                // Offsets are worked out from how much of the stream the decoder has consumed, and how much of that it
+                // discarded as garbage before the candidate frame.
+                decoder.take_discarded();
+                let before = pushed - decoder.buffered();
+                let Some(frame) = decoder.next_frame() else {
+                    break;
+                };
+                let start = before + decoder.take_discarded() as usize;
+                let end = pushed - decoder.buffered();
+                decoded.committed.push((start, end));
+                if let Ok(message) = frame {
+                    decoded.frames.push((start, message.into_bytes()));
+                }
+            }
+        }
+        decoded.committed.push((pushed - decoder.buffered(), pushed));
+        decoded
+    }
+
+    #[test]
+    fn encoded_message_round_trips() {
+        let encoder = Encoder::new().options(OPTION_SENSITIVE).version(Version::V2).message_type(7);
+        let message = encoder.encode(b"hello").unwrap();
+
+        let mut decoder = Decoder::new().with_validation(Validation::Strict);
+        decoder.push(message.as_bytes());
+        let decoded = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.payload(), b"hello");
+        assert_eq!(decoded.header().version(), Version::V2);
+        assert_eq!(decoded.header().message_type(), Some(7));
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    quickcheck! {
+        fn encoder_decoder_round_trip(part: Part) -> bool {
+            let frame = Part { corrupt: false, ..part }.frame();
+            let mut decoder = Decoder::new().with_validation(Validation::Strict);
+            decoder.push(&frame);
+            matches!(decoder.next_frame(), Some(Ok(message)) if message.as_bytes() == frame) && decoder.next_frame().is_none()
+        }
+
+        fn valid_frames_survive_noise_lenient(parts: Vec<Part>, chunks: Vec<u8>) -> bool {
+            let (stream, expected) = build_stream(&parts, Some(Validation::Lenient));
+            decode(&mut Decoder::new(), &stream, &chunks).frames == expected
+        }
+
+        fn valid_frames_survive_noise_strict(parts: Vec<Part>, chunks: Vec<u8>) -> bool {
+            let (stream, expected) = build_stream(&parts, Some(Validation::Strict));
+            decode(&mut Decoder::new().with_validation(Validation::Strict), &stream, &chunks).frames == expected
+        }
+
+        // With unfiltered noise a stray magic byte can start a header that passes validation, in which case the decoder
+        // commits to its length. A valid frame is only lost if it starts inside such a bogus frame.
+        fn valid_frames_only_lost_inside_bogus_frames_lenient(parts: Vec<Part>, chunks: Vec<u8>) -> bool {
+            let (stream, expected) = build_stream(&parts, None);
+            only_lost_inside_bogus_frames(&decode(&mut Decoder::new(), &stream, &chunks), &expected)
+        }
+
+        fn valid_frames_only_lost_inside_bogus_frames_strict(parts: Vec<Part>, chunks: Vec<u8>) -> bool {
+            let (stream, expected) = build_stream(&parts, None);
+            only_lost_inside_bogus_frames(&decode(&mut Decoder::new().with_validation(Validation::Strict), &stream, &chunks), &expected)
+        }
+    }
+
+    /// Function to check that every valid frame was either decoded where it was sent, or starts inside a range the
+    /// decoder committed to as another frame.
+    fn only_lost_inside_bogus_frames(decoded: &Decoded, expected: &[(usize, Vec<u8>)]) -> bool {
+        expected.iter().all(|frame| decoded.frames.contains(frame) || decoded.committed.iter().any(|&(start, end)| start < frame.0 && frame.0 < end))
+    }
+
+    #[test]
+    fn stray_header_swallows_following_frame() {
+        // A magic byte followed by bytes forming a non-sensitive header stating an 16 byte payload, with padding that
+        // only strict validation rejects.
+        let stray = [MAGIC, 0x00, 0x00, 0x10, 0x00, 0x00, 0x12, 0x34];
+        let frame = Encoder::new().encode(b"realdata").unwrap().into_bytes();
+        let stream = [&stray[..], &frame].concat();
+
+        // Lenient validation accepts the stray header and returns the real frame as its payload.
+        let lenient = decode(&mut Decoder::new(), &stream, &[]);
+        assert_eq!(lenient.messages(), vec![stream.clone()]);
+
+        // Strict validation rejects it and finds the real frame.
+        let strict = decode(&mut Decoder::new().with_validation(Validation::Strict), &stream, &[]);
+        assert_eq!(strict.messages(), vec![frame]);
+    }
+
+    #[test]
+    fn resync_work_is_linear_in_bytes_pushed() {
+        // Every offset in a run of magic bytes parses as a sensitive frame stating a 52 KB payload.
+        let garbage = vec![MAGIC; 256 * 1024];
+        let mut decoder = Decoder::new();
+        assert!(decode(&mut decoder, &garbage, &[255]).frames.is_empty());
+        assert!(decoder.hashed <= (VERIFY_CREDIT + 1) * garbage.len(), "hashed {} bytes", decoder.hashed);
+    }
+}
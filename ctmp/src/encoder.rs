@@ -0,0 +1,81 @@
+use std::fmt;
+
+use crate::checksum::compute_checksum;
+use crate::header::{CtmpHeader, HEADER_LEN, Version};
+use crate::message::CtmpMessage;
+
+/// Errors that can occur while encoding a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The payload does not fit in the 16 bit length field.
+    PayloadTooLong(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::PayloadTooLong(len) => write!(f, "payload of {} bytes exceeds the maximum of {} bytes", len, u16::MAX),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Builds CTMP messages with a correctly filled in length and checksum.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    options: u8,
+    version: Version,
+    message_type: u8,
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder {
+        Encoder { options: 0, version: Version::V1, message_type: 0 }
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Sets the options byte used for every message produced by this encoder.
+    pub fn options(mut self, options: u8) -> Encoder {
+        self.options = options;
+        self
+    }
+
+    /// Sets the protocol version of the messages produced by this encoder. Defaults to version 1.
+    pub fn version(mut self, version: Version) -> Encoder {
+        self.version = version;
+        self
+    }
+
+    /// Sets the message type written into version 2 messages. Ignored for version 1 messages.
+    pub fn message_type(mut self, message_type: u8) -> Encoder {
+        self.message_type = message_type;
+        self
+    }
+
+    /// Encodes `payload` into a complete message.
+    pub fn encode(&self, payload: &[u8]) -> Result<CtmpMessage, EncodeError> {
+        let length = u16::try_from(payload.len()).map_err(|_| EncodeError::PayloadTooLong(payload.len()))?;
+
+        let padding = match self.version {
+            Version::V1 => [0, 0],
+            Version::V2 => [Version::V2.number() << 4, self.message_type],
+        };
+
+        let mut header = CtmpHeader { options: self.options, length, checksum: 0, padding };
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(&header.to_bytes());
+        bytes.extend_from_slice(payload);
+
+        // The checksum field is ignored while computing the checksum, so it can be filled in afterwards.
+        header.checksum = compute_checksum(&bytes);
+        bytes[4..6].copy_from_slice(&header.checksum.to_be_bytes());
+
+        Ok(CtmpMessage::from_bytes(bytes).expect("encoded message has a valid header"))
+    }
+}
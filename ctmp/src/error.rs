@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Reason a frame was rejected by the [`Decoder`](crate::Decoder).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// A sensitive message's checksum does not match its contents.
+    ChecksumMismatch { stated: u16, computed: u16 },
+    /// The payload length stated in the header is above the decoder's maximum.
+    PayloadTooLarge { length: u16, max: usize },
+    /// Option bits other than the sensitive bit are set. Strict validation only.
+    ReservedOptionBits(u8),
+    /// A version 1 header's padding bytes are not zero. Strict validation only.
+    NonZeroPadding([u8; 2]),
+    /// The version field holds an unknown version. Strict validation only.
+    UnsupportedVersion(u8),
+    /// The reserved low nibble of a version 2 header's byte 6 is not zero. Strict validation only.
+    ReservedVersionBits(u8),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::ChecksumMismatch { stated, computed } => write!(f, "checksum 0x{:04X} does not match computed checksum 0x{:04X}", stated, computed),
+            FrameError::PayloadTooLarge { length, max } => write!(f, "payload length {} is above the maximum of {}", length, max),
+            FrameError::ReservedOptionBits(options) => write!(f, "reserved option bits are set in options byte 0x{:02X}", options),
+            FrameError::NonZeroPadding(padding) => write!(f, "padding bytes 0x{:02X}{:02X} are not zero", padding[0], padding[1]),
+            FrameError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            FrameError::ReservedVersionBits(byte) => write!(f, "reserved bits are set in version byte 0x{:02X}", byte),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// How strictly the [`Decoder`](crate::Decoder) checks the header fields of each frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Validation {
+    /// Only the checksum of sensitive messages is checked. Reserved option bits and padding are ignored, and headers
+    /// with an unknown version field are treated as version 1.
+    #[default]
+    Lenient,
+    /// Every reserved field must be zero and the version field must hold a known version, in addition to the checks
+    /// made in lenient mode.
+    Strict,
+}
+
+impl std::str::FromStr for Validation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Validation, String> {
+        match s {
+            "lenient" => Ok(Validation::Lenient),
+            "strict" => Ok(Validation::Strict),
+            _ => Err(format!("unknown validation mode '{}', expected lenient or strict", s)),
+        }
+    }
+}
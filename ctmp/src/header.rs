@@ -0,0 +1,149 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{FrameError, Validation};
+
+/// Magic byte marking the start of every CTMP message.
+pub const MAGIC: u8 = 0xCC;
+
+/// Length of the fixed CTMP header in bytes.
+pub const HEADER_LEN: usize = 8;
+
+/// Bit in the options byte marking a message as sensitive. Sensitive messages must carry a valid checksum.
+pub const OPTION_SENSITIVE: u8 = 0x40;
+
+/// Bits in the options byte that are reserved and must be zero under strict validation.
+pub const OPTIONS_RESERVED: u8 = !OPTION_SENSITIVE;
+
+/// Version of the CTMP protocol a message is sent with.
+///
+/// Version 1 headers end in two bytes of padding. Version 2 headers store the version number in the high nibble of
+/// byte 6 and a message type in byte 7. Version 1 padding is normally zero, so the version field of a version 1 header
+/// reads as 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// All versions understood by this library.
+    pub const ALL: [Version; 2] = [Version::V1, Version::V2];
+
+    /// The version number, as written in config files and handshakes.
+    pub fn number(self) -> u8 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for Version {
+    type Error = String;
+
+    fn try_from(number: u8) -> Result<Version, String> {
+        match number {
+            1 => Ok(Version::V1),
+            2 => Ok(Version::V2),
+            _ => Err(format!("unsupported CTMP version {}, expected 1 or 2", number)),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Version, String> {
+        let number: u8 = s.parse().map_err(|_| format!("invalid CTMP version '{}', expected 1 or 2", s))?;
+        Version::try_from(number)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+/// Decoded fields of a CTMP header.
+/// For version 1 messages `padding` holds the raw padding bytes; for version 2 messages it holds the version field and
+/// message type, which are read with [`CtmpHeader::version`] and [`CtmpHeader::message_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtmpHeader {
+    pub options: u8,
+    pub length: u16,
+    pub checksum: u16,
+    pub padding: [u8; 2],
+}
+
+impl CtmpHeader {
+    /// Parses a header from the start of `bytes`. Returns `None` if fewer than `HEADER_LEN` bytes are given
+    /// or the first byte is not the magic byte.
+    pub fn parse(bytes: &[u8]) -> Option<CtmpHeader> {
+        if bytes.len() < HEADER_LEN || bytes[0] != MAGIC {
+            return None;
+        }
+
+        Some(CtmpHeader {
+            options: bytes[1],
+            length: u16::from_be_bytes([bytes[2], bytes[3]]),
+            checksum: u16::from_be_bytes([bytes[4], bytes[5]]),
+            padding: [bytes[6], bytes[7]],
+        })
+    }
+
+    /// Serialises the header back into its 8 byte wire format.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let length = self.length.to_be_bytes();
+        let checksum = self.checksum.to_be_bytes();
+        [MAGIC, self.options, length[0], length[1], checksum[0], checksum[1], self.padding[0], self.padding[1]]
+    }
+
+    /// Returns true if the sensitive bit is set in the options byte.
+    pub fn is_sensitive(&self) -> bool {
+        self.options & OPTION_SENSITIVE != 0
+    }
+
+    /// Raw version field, the high nibble of byte 6. Always 0 for version 1 messages.
+    pub fn version_field(&self) -> u8 {
+        self.padding[0] >> 4
+    }
+
+    /// Version of the protocol the message was sent with. Version 1 senders are not required to zero the padding, so
+    /// any version field other than 2 is treated as version 1.
+    pub fn version(&self) -> Version {
+        if self.version_field() == Version::V2.number() { Version::V2 } else { Version::V1 }
+    }
+
+    /// Message type of a version 2 message. Version 1 messages do not have a message type.
+    pub fn message_type(&self) -> Option<u8> {
+        match self.version() {
+            Version::V1 => None,
+            Version::V2 => Some(self.padding[1]),
+        }
+    }
+
+    /// Checks the reserved header fields. Under lenient validation there is nothing to check.
+    pub fn validate(&self, validation: Validation) -> Result<(), FrameError> {
+        if validation == Validation::Lenient {
+            return Ok(());
+        }
+
+        if self.options & OPTIONS_RESERVED != 0 {
+            return Err(FrameError::ReservedOptionBits(self.options));
+        }
+        match self.version_field() {
+            0 if self.padding != [0, 0] => Err(FrameError::NonZeroPadding(self.padding)),
+            0 => Ok(()),
+            2 if self.padding[0] & 0x0F != 0 => Err(FrameError::ReservedVersionBits(self.padding[0])),
+            2 => Ok(()),
+            version => Err(FrameError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Total length of the message described by this header, including the header itself.
+    pub fn frame_len(&self) -> usize {
+        HEADER_LEN + self.length as usize
+    }
+}
@@ -0,0 +1,37 @@
+//! Library implementing the CTMP framing protocol used by the relay server.
+//!
+//! Every CTMP message starts with an 8 byte header:
+//!
+//! | Byte(s) | Field                                   |
+//! |---------|-----------------------------------------|
+//! | 0       | Magic byte (`0xCC`)                     |
+//! | 1       | Options (bit `0x40` marks sensitive)    |
+//! | 2..4    | Payload length (big-endian)             |
+//! | 4..6    | Checksum (big-endian)                   |
+//! | 6..8    | Padding (version 1)                     |
+//!
+//! Version 2 of the protocol replaces the padding with extended fields:
+//!
+//! | Byte(s) | Field                                   |
+//! |---------|-----------------------------------------|
+//! | 6       | Version (high nibble, `2`), reserved    |
+//! | 7       | Message type                            |
+//!
+//! Version 1 messages have zeroed padding, so both versions can be told apart from byte 6.
+//!
+//! The header is followed by `length` bytes of payload. [`Decoder`] turns a stream of bytes into [`CtmpMessage`]s and
+//! [`Encoder`] builds correctly checksummed messages from a payload.
+
+mod checksum;
+mod decoder;
+mod encoder;
+mod error;
+mod header;
+mod message;
+
+pub use checksum::{compute_checksum, verify_checksum};
+pub use decoder::Decoder;
+pub use encoder::{EncodeError, Encoder};
+pub use error::{FrameError, Validation};
+pub use header::{CtmpHeader, HEADER_LEN, MAGIC, OPTION_SENSITIVE, OPTIONS_RESERVED, Version};
+pub use message::CtmpMessage;
@@ -0,0 +1,56 @@
+use crate::checksum::{compute_checksum, verify_checksum};
+use crate::error::FrameError;
+use crate::header::{CtmpHeader, HEADER_LEN};
+
+/// A complete CTMP message. The original bytes are kept so that messages can be relayed byte-for-byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtmpMessage {
+    header: CtmpHeader,
+    bytes: Vec<u8>,
+}
+
+impl CtmpMessage {
+    /// Builds a message from the raw bytes of a complete frame. Returns `None` if the bytes do not start with a
+    /// valid header or their length does not match the length stated in the header.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<CtmpMessage> {
+        let header = CtmpHeader::parse(&bytes)?;
+        if bytes.len() != header.frame_len() {
+            return None;
+        }
+        Some(CtmpMessage { header, bytes })
+    }
+
+    pub fn header(&self) -> &CtmpHeader {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.bytes[HEADER_LEN..]
+    }
+
+    /// The complete message as it appears on the wire.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_sensitive(&self) -> bool {
+        self.header.is_sensitive()
+    }
+
+    /// Returns true if the checksum stated in the header matches the message contents.
+    pub fn checksum_valid(&self) -> bool {
+        verify_checksum(&self.bytes, self.header.checksum)
+    }
+
+    /// Checks the checksum if the message is sensitive. Checksums of other messages are not checked.
+    pub fn verify(&self) -> Result<(), FrameError> {
+        if self.is_sensitive() && !self.checksum_valid() {
+            return Err(FrameError::ChecksumMismatch { stated: self.header.checksum, computed: compute_checksum(&self.bytes) });
+        }
+        Ok(())
+    }
+}
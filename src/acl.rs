@@ -0,0 +1,257 @@
+//! IP allow and deny lists, checked when a client connects to either listener so that only approved hosts can publish
+//! or subscribe.
+//!
+//! Each list is made of CIDR blocks such as `10.0.0.0/8` or `2001:db8::/32`; a plain address matches only itself. A
+//! client is refused if its address is in the deny list, or if the allow list is not empty and its address is not in
+//! it. The deny list takes precedence, so a single host can be refused from an allowed network.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use tracing::warn;
+
+use crate::metrics::{self, Metrics};
+use crate::transport::Peer;
+
+/// A block of IPv4 or IPv6 addresses sharing a prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address is in the block. IPv4 clients connecting to a dual-stack listener appear as
+    /// IPv4-mapped IPv6 addresses, so those are matched as the IPv4 address they carry, and also as they are, for blocks
+    /// written in the mapped form such as `::ffff:10.0.0.0/104`.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.contains_exactly(addr.to_canonical()) || self.contains_exactly(addr)
+    }
+
+    fn contains_exactly(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => prefix_matches(network.to_bits().into(), addr.to_bits().into(), 32, self.prefix_len),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => prefix_matches(network.to_bits(), addr.to_bits(), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_len).ok_or_else(|| format!("invalid prefix length in '{}', expected 0 to {}", s, max_len))?,
+            None => max_len,
+        };
+
+        // Bits set after the prefix are most likely a typo, e.g. 10.0.0.1/8 for 10.0.0.0/8, so they are refused rather
+        // than silently widening or narrowing the block.
+        if network_bits(network) & !mask(max_len, prefix_len) != 0 {
+            return Err(format!("'{}' has bits set after the prefix length", s));
+        }
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// The allow and deny lists of one listener.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: &[Cidr], deny: &[Cidr]) -> AccessList {
+        AccessList { allow: allow.to_vec(), deny: deny.to_vec() }
+    }
+
+    /// Returns true if a client with the given address may connect.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// Checks a newly accepted client of the named listener, logging and counting it if it is refused. Returns true if
+    /// the client may connect. Clients of a Unix domain socket have no IP address and are always admitted, as the
+    /// socket's file permissions control who can connect.
+    pub fn admit(&self, peer: &Peer, listener: &str, metrics: &Metrics) -> bool {
+        if peer.ip().is_none_or(|ip| self.permits(ip)) {
+            return true;
+        }
+        warn!(%peer, listener, "connection refused by IP access list");
+        metrics::add(&metrics.connections_denied, 1);
+        false
+    }
+}
+
+/// Function to check whether the first `prefix_len` bits of two addresses of `len` bits are equal.
+fn prefix_matches(network: u128, addr: u128, len: u8, prefix_len: u8) -> bool {
+    (network ^ addr) & mask(len, prefix_len) == 0
+}
+
+/// Function to build the mask selecting the first `prefix_len` bits of an address of `len` bits.
+fn mask(len: u8, prefix_len: u8) -> u128 {
+    let all = if len == 128 { u128::MAX } else { (1u128 << len) - 1 };
+    match all.checked_shr(u32::from(prefix_len)) {
+        Some(host_bits) => all & !host_bits,
+        None => all,
+    }
+}
+
+/// Function to get the bits of an address as a number.
+fn network_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => addr.to_bits().into(),
+        IpAddr::V6(addr) => addr.to_bits(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap_or_else(|e| panic!("'{}' did not parse: {}", s, e))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn access_list(allow: &[&str], deny: &[&str]) -> AccessList {
+        let allow: Vec<Cidr> = allow.iter().map(|s| cidr(s)).collect();
+        let deny: Vec<Cidr> = deny.iter().map(|s| cidr(s)).collect();
+        AccessList::new(&allow, &deny)
+    }
+
+    #[test]
+    fn zero_prefix_matches_whole_family() {
+        let v4 = cidr("0.0.0.0/0");
+        assert!(v4.contains(ip("0.0.0.0")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("::1")));
+
+        let v6 = cidr("::/0");
+        assert!(v6.contains(ip("::")));
+        assert!(v6.contains(ip("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let v4 = cidr("192.0.2.7/32");
+        assert!(v4.contains(ip("192.0.2.7")));
+        assert!(!v4.contains(ip("192.0.2.6")));
+        assert!(!v4.contains(ip("192.0.2.8")));
+
+        let v6 = cidr("2001:db8::7/128");
+        assert!(v6.contains(ip("2001:db8::7")));
+        assert!(!v6.contains(ip("2001:db8::6")));
+        assert!(!v6.contains(ip("2001:db8::8")));
+    }
+
+    #[test]
+    fn address_without_prefix_matches_itself() {
+        assert_eq!(cidr("192.0.2.7"), cidr("192.0.2.7/32"));
+        assert_eq!(cidr("2001:db8::7"), cidr("2001:db8::7/128"));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(ip("192.0.2.70")));
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+    }
+
+    #[test]
+    fn prefix_boundaries() {
+        let v4 = cidr("10.1.0.0/16");
+        assert!(v4.contains(ip("10.1.0.0")));
+        assert!(v4.contains(ip("10.1.255.255")));
+        assert!(!v4.contains(ip("10.0.255.255")));
+        assert!(!v4.contains(ip("10.2.0.0")));
+
+        // A prefix length that is not a multiple of 8.
+        let v6 = cidr("2001:db8::/33");
+        assert!(v6.contains(ip("2001:db8:7fff::1")));
+        assert!(!v6.contains(ip("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_blocks() {
+        // A dual-stack listener reports an IPv4 client as ::ffff:a.b.c.d.
+        let v4 = cidr("10.0.0.0/8");
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::ffff:11.1.2.3")));
+        // Only mapped addresses are treated as IPv4, not other IPv6 addresses with the same low bits.
+        assert!(!v4.contains(ip("::10.1.2.3")));
+        assert!(!v4.contains(ip("64:ff9b::10.1.2.3")));
+
+        // Blocks written in the mapped form match both forms of the address.
+        let mapped = cidr("::ffff:10.0.0.0/104");
+        assert!(mapped.contains(ip("::ffff:10.1.2.3")));
+        assert!(!mapped.contains(ip("::ffff:11.1.2.3")));
+
+        let list = access_list(&[], &["192.0.2.0/24"]);
+        assert!(!list.permits(ip("::ffff:192.0.2.1")));
+        assert!(list.permits(ip("::ffff:198.51.100.1")));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let list = access_list(&["10.0.0.0/8", "2001:db8::/32"], &["10.0.0.5", "2001:db8::5"]);
+        assert!(list.permits(ip("10.0.0.4")));
+        assert!(!list.permits(ip("10.0.0.5")));
+        assert!(!list.permits(ip("::ffff:10.0.0.5")));
+        assert!(list.permits(ip("2001:db8::4")));
+        assert!(!list.permits(ip("2001:db8::5")));
+        // Addresses outside the allow list are refused.
+        assert!(!list.permits(ip("192.0.2.1")));
+
+        // An address both allowed and denied exactly is refused.
+        assert!(!access_list(&["192.0.2.1"], &["192.0.2.1"]).permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn empty_lists_permit_everyone() {
+        let list = AccessList::default();
+        assert!(list.permits(ip("192.0.2.1")));
+        assert!(list.permits(ip("::1")));
+
+        let deny_only = access_list(&[], &["192.0.2.0/24"]);
+        assert!(!deny_only.permits(ip("192.0.2.1")));
+        assert!(deny_only.permits(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        for (s, error) in [
+            ("10.0.0.0/33", "invalid prefix length in '10.0.0.0/33', expected 0 to 32"),
+            ("::/129", "invalid prefix length in '::/129', expected 0 to 128"),
+            ("10.0.0.0/", "invalid prefix length"),
+            ("10.0.0.0/-1", "invalid prefix length"),
+            ("10.0.0.0/8/8", "invalid prefix length"),
+            ("10.0.0/8", "invalid IP address '10.0.0'"),
+            ("localhost", "invalid IP address 'localhost'"),
+            ("", "invalid IP address ''"),
+            ("10.0.0.1/8", "'10.0.0.1/8' has bits set after the prefix length"),
+            ("2001:db8::1/32", "'2001:db8::1/32' has bits set after the prefix length"),
+        ] {
+            let result = s.parse::<Cidr>();
+            assert!(matches!(&result, Err(e) if e.starts_with(error)), "'{}' gave {:?}", s, result);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Local admin interface for inspecting and managing a running relay, served on a Unix domain socket.
+//!
+//! Each command is a single line, answered with zero or more lines of output followed by `OK`, or by
+//! `ERR <reason>` if the command failed. Several commands can be sent on one connection. The `ctmp-admin` binary
+//! sends a single command and prints the output.
+//!
+//! | Command                   | Output                                                                    |
+//! |---------------------------|---------------------------------------------------------------------------|
+//! | `list-destinations`       | one line per destination client, with its ID, address and subscription    |
+//! | `show-source`             | one line per source client being relayed, with its session ID and channel |
+//! | `kick <id>`               | disconnects the destination client with the given ID                      |
+//! | `stats`                   | the metrics, one `name value` line each                                   |
+//! | `set-log-level <filter>`  | changes the log level, e.g. `debug` or `warn,tcp_server::source=debug`    |
+//! | `help`                    | the list of commands                                                      |
+//!
+//! Anyone who can connect to the socket can manage the relay, so it is created readable and writable by its owner only.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use tracing::{debug, info, warn};
+
+use crate::destination::Destinations;
+use crate::logging::LogLevel;
+use crate::metrics::Metrics;
+use crate::source::ConnectedSources;
+use crate::subscription;
+use crate::transport::{self, AcceptBackoff};
+
+/// Maximum length of a command line, so a client cannot make the relay buffer an endless line.
+const MAX_COMMAND_LEN: u64 = 1024;
+
+const HELP: &str = "\
+list-destinations       list the connected destination clients
+show-source             show the source clients being relayed
+kick <id>               disconnect a destination client
+stats                   show the metrics
+set-log-level <filter>  change the log level, e.g. debug or warn,tcp_server::source=debug
+help                    show this list
+";
+
+/// The parts of the relay the admin commands inspect and manage.
+pub struct Admin {
+    pub destinations: Arc<Destinations>,
+    pub sources: Arc<ConnectedSources>,
+    pub metrics: Arc<Metrics>,
+    pub log_level: LogLevel,
+}
+
+/// Function to start serving admin commands on a Unix domain socket at `path`. A socket file left behind by a relay
+/// that is no longer running is replaced, but the relay refuses to start if another relay is using the socket.
+pub fn serve(path: &Path, admin: Admin) -> io::Result<()> {
+    let listener = transport::bind_unix(path, 0o600)?;
+
+    // Each admin client gets its own thread, so a client left connected does not block the others.
+    let admin = Arc::new(admin);
+    thread::spawn(move || {
+        let mut backoff = AcceptBackoff::new();
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    thread::sleep(backoff.failed(&e, "admin"));
+                    continue;
+                }
+            };
+            backoff.succeeded();
+            let admin = Arc::clone(&admin);
+            thread::spawn(move || {
+                if let Err(e) = respond(stream, &admin) {
+                    warn!(error = %e, "failed to serve admin client");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Function to answer the commands sent by an admin client until it disconnects.
+fn respond(stream: UnixStream, admin: &Admin) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.by_ref().take(MAX_COMMAND_LEN).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 >= MAX_COMMAND_LEN {
+            writer.write_all(b"ERR command too long\n")?;
+            return Ok(());
+        }
+
+        let reply = match run(line.trim(), admin) {
+            Ok(output) => output + "OK\n",
+            Err(reason) => format!("ERR {}\n", reason),
+        };
+        writer.write_all(reply.as_bytes())?;
+    }
+}
+
+/// Function to run a single command, returning its output or the reason it failed.
+fn run(command: &str, admin: &Admin) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let argument = words.next();
+    if words.next().is_some() {
+        return Err(format!("too many arguments for '{}'", name));
+    }
+    debug!(command, "admin command");
+
+    match (name, argument) {
+        ("list-destinations", None) => Ok(list_destinations(admin)),
+        ("show-source", None) => Ok(show_source(admin)),
+        ("kick", Some(id)) => {
+            let id: u64 = id.parse().map_err(|_| format!("invalid destination ID '{}'", id))?;
+            if !admin.destinations.kick(id) {
+                return Err(format!("no destination with ID {}", id));
+            }
+            Ok(String::new())
+        }
+        ("stats", None) => {
+            // The metrics in the Prometheus text format, without the help and type comments.
+            let rendered = admin.metrics.render(admin.destinations.len(), &admin.destinations.queue_depths());
+            Ok(rendered.lines().filter(|line| !line.starts_with('#')).map(|line| format!("{}\n", line)).collect())
+        }
+        ("set-log-level", Some(level)) => {
+            admin.log_level.set(level)?;
+            info!(level, "log level changed");
+            Ok(String::new())
+        }
+        ("help", None) => Ok(HELP.to_string()),
+        ("list-destinations" | "show-source" | "stats" | "help", Some(_)) => Err(format!("'{}' takes no argument", name)),
+        ("kick" | "set-log-level", None) => Err(format!("'{}' requires an argument", name)),
+        _ => Err(format!("unknown command '{}', send help for the list of commands", name)),
+    }
+}
+
+/// Function to describe each connected destination client on its own line.
+fn list_destinations(admin: &Admin) -> String {
+    let mut output = String::new();
+    for dest in admin.destinations.list() {
+        let subscription = &dest.subscription;
+        output.push_str(&format!(
+            "id={} peer={} connected_secs={} versions={} channels={} queued={}",
+            dest.id,
+            dest.peer,
+            dest.connected.as_secs(),
+            subscription::join(&subscription.versions),
+            subscription.channels.join(","),
+            dest.queued
+        ));
+        if !subscription.filter.is_empty() {
+            output.push_str(&format!(" filter={}", subscription.filter));
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Function to describe each source client being relayed on its own line.
+fn show_source(admin: &Admin) -> String {
+    admin
+        .sources
+        .list()
+        .iter()
+        .map(|source| format!("session={} peer={} channel={} connected_secs={}\n", source.session, source.peer, source.channel, source.connected.as_secs()))
+        .collect()
+}
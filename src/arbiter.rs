@@ -0,0 +1,191 @@
+//! Arbitration between several concurrently connected source clients in multi-source mode.
+//!
+//! Each source thread pushes its decoded messages into its own bounded queue, and a single relay thread takes them out
+//! one whole message at a time, so messages from different sources are only ever interleaved at message boundaries.
+//! When a source's queue is full its thread blocks, which stops reading from that source without affecting the others.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+
+use ctmp::{CtmpMessage, HEADER_LEN};
+
+use crate::subscription::Channel;
+
+/// How the relay thread chooses which source's message to relay next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fairness {
+    /// Take one message from each source with messages waiting in turn.
+    RoundRobin,
+    /// Deficit round robin: each source may send up to the same number of bytes per turn, so a source sending large
+    /// messages does not get a larger share than one sending small messages.
+    ByteFair,
+}
+
+impl FromStr for Fairness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fairness, String> {
+        match s {
+            "round-robin" => Ok(Fairness::RoundRobin),
+            "byte-fair" => Ok(Fairness::ByteFair),
+            _ => Err(format!("unknown fairness policy '{}', expected round-robin or byte-fair", s)),
+        }
+    }
+}
+
+impl fmt::Display for Fairness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fairness::RoundRobin => "round-robin",
+            Fairness::ByteFair => "byte-fair",
+        })
+    }
+}
+
+/// Messages waiting to be relayed from a single source.
+struct SourceQueue {
+    session: u64,
+    messages: VecDeque<(Channel, CtmpMessage)>,
+    /// Bytes the source may still send in the current turn, used by the byte-fair policy.
+    deficit: usize,
+    /// Set once the source has disconnected. The queue is removed once its remaining messages have been relayed.
+    closed: bool,
+}
+
+struct ArbiterState {
+    sources: Vec<SourceQueue>,
+    /// Index of the source whose turn it is.
+    turn: usize,
+    /// Set when the relay is shutting down, so the relay thread stops once every queue is empty.
+    closed: bool,
+}
+
+/// Queues of the connected source clients, shared between the source threads and the relay thread.
+pub struct Arbiter {
+    state: Mutex<ArbiterState>,
+    /// Signalled when a message is queued, waking the relay thread.
+    ready: Condvar,
+    /// Signalled when a message is taken out, waking source threads waiting for space.
+    space: Condvar,
+    queue_depth: usize,
+    fairness: Fairness,
+    /// Bytes added to a source's deficit each turn. Large enough for any accepted message, so every source with a
+    /// message waiting can send at least one per turn.
+    quantum: usize,
+}
+
+impl Arbiter {
+    pub fn new(queue_depth: usize, fairness: Fairness, max_payload: usize) -> Arbiter {
+        Arbiter {
+            state: Mutex::new(ArbiterState { sources: Vec::new(), turn: 0, closed: false }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            queue_depth,
+            fairness,
+            quantum: HEADER_LEN + max_payload,
+        }
+    }
+
+    /// Adds a queue for a newly connected source. Returns false if `max_sources` sources are already connected.
+    pub fn register(&self, session: u64, max_sources: Option<usize>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let connected = state.sources.iter().filter(|queue| !queue.closed).count();
+        if max_sources.is_some_and(|max| connected >= max) {
+            return false;
+        }
+        state.sources.push(SourceQueue { session, messages: VecDeque::new(), deficit: 0, closed: false });
+        true
+    }
+
+    /// Marks a source as disconnected. Messages it already queued are still relayed.
+    pub fn unregister(&self, session: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(queue) = state.sources.iter_mut().find(|queue| queue.session == session) {
+            queue.closed = true;
+        }
+        // Wake the relay thread so it can remove the queue if it is empty.
+        self.ready.notify_one();
+    }
+
+    /// Queues a message from a source, along with the channel it is relayed on, blocking while that source's queue is
+    /// full.
+    pub fn push(&self, session: u64, channel: &Channel, message: CtmpMessage) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let Some(queue) = state.sources.iter_mut().find(|queue| queue.session == session) else {
+                return;
+            };
+            if queue.messages.len() < self.queue_depth {
+                queue.messages.push_back((Channel::clone(channel), message));
+                self.ready.notify_one();
+                return;
+            }
+            state = self.space.wait(state).unwrap();
+        }
+    }
+
+    /// Takes out the next message to relay according to the fairness policy, blocking until one is available.
+    /// Returns `None` once the arbiter has been closed and every queued message has been relayed.
+    pub fn next(&self) -> Option<(Channel, CtmpMessage)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            // Queues of disconnected sources are removed once everything they queued has been relayed.
+            state.sources.retain(|queue| !(queue.closed && queue.messages.is_empty()));
+
+            if let Some(message) = self.take(&mut state) {
+                self.space.notify_all();
+                return Some(message);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Closes the arbiter on shutdown, once the source sessions have ended. The relay thread carries on until every
+    /// queued message has been relayed.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Takes a message from the source whose turn it is, moving the turn on as the policy requires.
+    fn take(&self, state: &mut ArbiterState) -> Option<(Channel, CtmpMessage)> {
+        let count = state.sources.len();
+        if state.sources.iter().all(|queue| queue.messages.is_empty()) {
+            return None;
+        }
+
+        loop {
+            state.turn %= count;
+            let queue = &mut state.sources[state.turn];
+
+            let Some((_, front)) = queue.messages.front() else {
+                // An idle source does not build up credit for later turns.
+                queue.deficit = 0;
+                state.turn += 1;
+                continue;
+            };
+
+            match self.fairness {
+                Fairness::RoundRobin => {
+                    state.turn += 1;
+                    return queue.messages.pop_front();
+                }
+                Fairness::ByteFair => {
+                    let len = front.as_bytes().len();
+                    if queue.deficit >= len {
+                        queue.deficit -= len;
+                        return queue.messages.pop_front();
+                    }
+                    // Not enough credit left this turn, so top it up and move on to the next source.
+                    queue.deficit += self.quantum;
+                    state.turn += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! Tokio based implementation of the relay, enabled with the `async` cargo feature.
+//!
+//! Destinations are served by a task each instead of a thread each, and messages are fanned out with a
+//! `tokio::sync::broadcast` channel, so thousands of destination clients can be connected at once.
+//!
+//! Shutdown on SIGINT or SIGTERM works as in the threaded relay (see the `shutdown` module): the listeners are closed,
+//! each source session relays the complete messages already received and ends, and then each destination is sent the
+//! messages still waiting for it, until the shutdown timeout.
+
+use std::io;
+use std::net::IpAddr;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use ctmp::{CtmpMessage, Version};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tokio::sync::{Notify, broadcast, watch};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{Instrument, debug, field, info, info_span, warn};
+
+use crate::acl::AccessList;
+use crate::auth::{self, Authenticator, SourceAuth};
+use crate::config::Config;
+use crate::destination::OverflowPolicy;
+use crate::metrics::{self, Metrics};
+use crate::source::{self, BLOCKED_REASON, FrameProcessor, PREEMPTED_REPLY, SourceSlot};
+use crate::subscription::{self, Channel, MAX_HELLO_LEN, SOURCE_HELLO_START, Subscription};
+use crate::transport::{AcceptBackoff, ListenAddr, Peer};
+
+/// A message broadcast to the destination tasks, along with the channel it is relayed on.
+type Relayed = (Channel, Arc<CtmpMessage>);
+
+/// Function to run the async relay. Blocks the calling thread, and returns once the relay has shut down on a signal,
+/// or with an error if it cannot start, e.g. because a listener cannot be bound.
+/// The drop-newest overflow policy is rejected when the config is validated, as the broadcast channel always discards
+/// the oldest messages of a lagging receiver.
+pub fn run(config: &Config) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_io().enable_time().build()?;
+    let result = runtime.block_on(serve(config));
+    // Sessions still waiting for the source slot are on the blocking thread pool, and must not hold up the exit.
+    runtime.shutdown_background();
+    result
+}
+
+async fn serve(config: &Config) -> io::Result<()> {
+    // Each destination client task holds a receiver of this channel. The channel capacity acts as the queue depth of
+    // every destination.
+    let (sender, _) = broadcast::channel::<Relayed>(config.dest_queue_depth);
+
+    // Set once a signal is received, to stop accepting clients and stop reading from the sources, and then once the
+    // source sessions have ended, to have the destinations send what is left for them and disconnect.
+    let (stop, stopping) = watch::channel(false);
+    let (drain, draining) = watch::channel(false);
+    let shutdown_timeout = Duration::from_millis(config.shutdown_timeout_ms);
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        let signal = next_signal(&mut interrupt, &mut terminate).await;
+        info!(signal, timeout_ms = shutdown_timeout.as_millis() as u64, "shutting down");
+        stop.send_replace(true);
+        let signal = next_signal(&mut interrupt, &mut terminate).await;
+        warn!(signal, "second signal received, exiting without draining");
+        process::exit(1);
+    });
+
+    // HTTP server exposing the metrics, if enabled. Destinations are counted once their handshake has completed, as
+    // the receiver count also includes destinations still sending their hello. The broadcast channel has no
+    // per-destination queues to report.
+    let metrics = Arc::new(Metrics::default());
+    let connected = Arc::new(AtomicU64::new(0));
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics = Arc::clone(&metrics);
+        let connected = Arc::clone(&connected);
+        metrics::serve(metrics_addr, move || metrics.render(connected.load(Ordering::Relaxed) as usize, &[]))?;
+        info!(addr = %metrics_addr, "serving metrics");
+    }
+
+    // The async relay listens on a single TCP address for each side, which is checked when the config is validated.
+    let ([ListenAddr::Tcp(source_addr)], [ListenAddr::Tcp(dest_addr)]) = (&config.source_addr[..], &config.dest_addr[..]) else {
+        unreachable!("rejected when validating the config");
+    };
+
+    // TcpListener for the single source client.
+    let source_listener = TcpListener::bind(source_addr).await?;
+
+    // TcpListener for the destination clients.
+    let dest_listener = TcpListener::bind(dest_addr).await?;
+
+    info!(%source_addr, %dest_addr, "async relay listening");
+
+    // Task to run in the background until shutdown, accepting new destination clients. Returns the destination tasks,
+    // so the shutdown can wait for them to finish.
+    let dest_sender = sender.clone();
+    let overflow_policy = config.dest_overflow;
+    let max_destinations = config.max_destinations;
+    let versions = Arc::new(config.source_versions.clone());
+    let hello_timeout = Duration::from_millis(config.dest_hello_timeout_ms);
+    let dest_metrics = Arc::clone(&metrics);
+    let dest_acl = AccessList::new(&config.dest_allow, &config.dest_deny);
+    let mut dest_stopping = stopping.clone();
+    let dest_accept = tokio::spawn(async move {
+        let mut destinations = JoinSet::new();
+        let mut backoff = AcceptBackoff::new();
+        for id in 1u64.. {
+            let (stream, peer) = tokio::select! {
+                accepted = dest_listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tokio::time::sleep(backoff.failed(&e, "destination")).await;
+                        continue;
+                    }
+                },
+                _ = wait_set(&mut dest_stopping) => break,
+            };
+            backoff.succeeded();
+            // Tasks of destinations that have disconnected are no longer needed.
+            while destinations.try_join_next().is_some() {}
+            if !dest_acl.admit(&Peer::Tcp(peer), "destination", &dest_metrics) {
+                continue;
+            }
+            let span = info_span!("destination", id, %peer);
+
+            // Every destination task holds one receiver, so the receiver count is the number of destinations.
+            if max_destinations.is_some_and(|max| dest_sender.receiver_count() >= max) {
+                span.in_scope(|| warn!(max_destinations, "destination rejected, maximum number of destinations connected"));
+                continue;
+            }
+            // Subscribing before the handshake means no messages are missed while it takes place.
+            let receiver = dest_sender.subscribe();
+            let versions = Arc::clone(&versions);
+            let metrics = Arc::clone(&dest_metrics);
+            let connected = Arc::clone(&connected);
+            let draining = draining.clone();
+            destinations.spawn(
+                async move {
+                    let mut stream = stream;
+                    match handshake(&mut stream, &versions, hello_timeout).await {
+                        Ok(subscription) => {
+                            info!(versions = %subscription::join(&subscription.versions), channels = %subscription.channels.join(","), filter = %subscription.filter, "destination connected");
+                            metrics::add(&connected, 1);
+                            write_destination(stream, receiver, subscription, overflow_policy, draining, &metrics).await;
+                            metrics::sub(&connected);
+                            info!("destination disconnected");
+                        }
+                        Err(e) => warn!(error = %e, "destination handshake failed"),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+        destinations
+    });
+
+    // Loop to run continuously, allowing a new source client to connect if the current client disconnects.
+    // Errors are contained to the source session they happen in, so destination clients stay connected.
+    // Each source connection gets a session ID, which is attached to everything logged during the session.
+    // Each connection is handled by its own task, so a source connecting while another is connected is dealt with
+    // straight away according to the conflict policy.
+    let slot = Arc::new(SourceSlot::new(config.source_conflict, Duration::from_millis(config.source_wait_timeout_ms)));
+    let auth = Arc::new(Authenticator::new(config)?);
+    let source_acl = AccessList::new(&config.source_allow, &config.source_deny);
+    let config = Arc::new(config.clone());
+    let mut sessions = JoinSet::new();
+    let mut session: u64 = 0;
+    let mut source_stopping = stopping.clone();
+    let mut backoff = AcceptBackoff::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = source_listener.accept() => accepted,
+            _ = wait_set(&mut source_stopping) => break,
+        };
+        let (source_stream, source_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tokio::time::sleep(backoff.failed(&e, "source")).await;
+                continue;
+            }
+        };
+        backoff.succeeded();
+        // Tasks of sessions that have ended are no longer needed.
+        while sessions.try_join_next().is_some() {}
+        if !source_acl.admit(&Peer::Tcp(source_addr), "source", &metrics) {
+            continue;
+        }
+        session += 1;
+
+        let span = info_span!("source", session, peer = %source_addr, channel = field::Empty);
+        let slot = Arc::clone(&slot);
+        let auth = Arc::clone(&auth);
+        let sender = sender.clone();
+        let metrics = Arc::clone(&metrics);
+        let config = Arc::clone(&config);
+        let mut stopping = stopping.clone();
+        sessions.spawn(
+            async move {
+                let mut source_stream = source_stream;
+                // A source that has not completed its handshake has sent no messages, so it is simply disconnected on
+                // shutdown.
+                let handshake = tokio::select! {
+                    result = source_handshake(&mut source_stream, source_addr.ip(), &auth, &metrics) => result,
+                    _ = wait_set(&mut stopping) => Err(io::Error::other("relay shutting down")),
+                };
+                let channel = match handshake {
+                    Ok(channel) => channel,
+                    Err(e) => {
+                        warn!(error = %e, "source handshake failed");
+                        let _ = source_stream.shutdown().await;
+                        return;
+                    }
+                };
+                tracing::Span::current().record("channel", &*channel);
+
+                // Preempting a session notifies its read loop, which tells the source why and ends the session.
+                // Waiting for the slot blocks, so it is done on the blocking thread pool.
+                let preempted = Arc::new(Notify::new());
+                let stop = {
+                    let preempted = Arc::clone(&preempted);
+                    Box::new(move || preempted.notify_one())
+                };
+                let acquired = {
+                    let slot = Arc::clone(&slot);
+                    let metrics = Arc::clone(&metrics);
+                    let span = tracing::Span::current();
+                    tokio::task::spawn_blocking(move || span.in_scope(|| slot.acquire(session, stop, &metrics))).await
+                };
+                match acquired {
+                    Ok(Ok(())) => {}
+                    Ok(Err(reason)) => {
+                        warn!(reason, "source rejected");
+                        metrics::add(&metrics.sources_rejected, 1);
+                        let _ = source_stream.write_all(subscription::error_reply(reason).as_bytes()).await;
+                        let _ = source_stream.shutdown().await;
+                        return;
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "failed to set up source session");
+                        return;
+                    }
+                }
+
+                info!("source connected");
+                metrics::add(&metrics.sources_connected, 1);
+                match handle_source(&mut source_stream, &channel, &preempted, &mut stopping, &sender, &metrics, &config).await {
+                    Ok(()) => info!("source disconnected"),
+                    Err(e) => warn!(error = %e, "source disconnected with error"),
+                }
+                metrics::sub(&metrics.sources_connected);
+                let _ = source_stream.shutdown().await;
+                slot.release(session);
+            }
+            .instrument(span),
+        );
+    }
+
+    // The listeners are closed, so no new clients can connect while the relay drains.
+    drop(source_listener);
+    let deadline = Instant::now() + shutdown_timeout;
+    if tokio::time::timeout_at(deadline, async { while sessions.join_next().await.is_some() {} }).await.is_err() {
+        warn!(running = sessions.len(), "shutdown timeout passed before the source sessions ended");
+    }
+
+    // Every message the sources sent has been broadcast, so the destinations send what is left for them and disconnect.
+    drain.send_replace(true);
+    let mut destinations = dest_accept.await.map_err(io::Error::other)?;
+    if tokio::time::timeout_at(deadline, async { while destinations.join_next().await.is_some() {} }).await.is_err() {
+        warn!(writing = destinations.len(), "shutdown timeout passed before every destination was sent its queued messages");
+    }
+    info!("relay shut down");
+    Ok(())
+}
+
+/// Function to wait until a shutdown flag is set. Returns straight away if it already is.
+async fn wait_set(flag: &mut watch::Receiver<bool>) {
+    // The sender is only dropped when the relay exits, so an error means there is nothing left to wait for.
+    let _ = flag.wait_for(|set| *set).await;
+}
+
+/// Function to wait for the next SIGINT or SIGTERM, returning its number.
+async fn next_signal(interrupt: &mut Signal, terminate: &mut Signal) -> i32 {
+    tokio::select! {
+        _ = interrupt.recv() => SignalKind::interrupt().as_raw_value(),
+        _ = terminate.recv() => SignalKind::terminate().as_raw_value(),
+    }
+}
+
+/// Function to handle the messages sent by the current source client. Function is exited when the source disconnects
+/// or is preempted, once the messages already received have been relayed after shutdown is requested, or with an
+/// error if reading from the source fails.
+async fn handle_source(
+    source_stream: &mut TcpStream,
+    channel: &Channel,
+    preempted: &Notify,
+    stopping: &mut watch::Receiver<bool>,
+    sender: &broadcast::Sender<Relayed>,
+    metrics: &Metrics,
+    config: &Config,
+) -> io::Result<()> {
+    let mut frames = FrameProcessor::new(metrics, config);
+    let mut read_buffer = [0u8; 1024];
+    // Set on shutdown, after which only what the source has already sent is read.
+    let mut draining = false;
+
+    loop {
+        // Only read as much as the decoder's buffer has space for, so a source cannot make it grow without bound.
+        let read_len = read_buffer.len().min(frames.space());
+        let bytes_read = if draining {
+            match source_stream.try_read(&mut read_buffer[..read_len]) {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        } else {
+            tokio::select! {
+                result = source_stream.read(&mut read_buffer[..read_len]) => result?,
+                _ = preempted.notified() => {
+                    let _ = source_stream.write_all(PREEMPTED_REPLY.as_bytes()).await;
+                    break;
+                }
+                _ = wait_set(stopping) => {
+                    draining = true;
+                    continue;
+                }
+            }
+        };
+        if bytes_read == 0 { // If the source disconnects, exit the loop.
+            break;
+        }
+        frames.push(&read_buffer[..bytes_read], |message| {
+            // Sending only fails when there are no destination clients, in which case the message is dropped.
+            metrics::add(&metrics.frames_relayed, 1);
+            let _ = sender.send((Channel::clone(channel), Arc::new(message)));
+        });
+    }
+    frames.finish();
+    Ok(())
+}
+
+/// Function to perform the handshake with a newly connected source client: authentication if enabled, and the hello
+/// line naming its channel. Returns the channel, or an error once the source has been told why it was turned away.
+/// See the `subscription` and `auth` modules.
+async fn source_handshake(stream: &mut TcpStream, peer: IpAddr, auth: &Authenticator, metrics: &Metrics) -> io::Result<Channel> {
+    let authenticate = auth.method() != SourceAuth::None;
+    if source::is_blocked(Some(peer), auth, metrics) {
+        return Err(refuse(stream, BLOCKED_REASON).await);
+    }
+
+    let nonce = auth.challenge()?;
+    if let Some(nonce) = &nonce {
+        stream.write_all(auth::challenge_line(nonce).as_bytes()).await?;
+    }
+
+    // An unauthenticated client is only given a limited time to prove itself.
+    let line = if authenticate {
+        tokio::time::timeout(auth.timeout(), read_source_hello(stream)).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for hello"))??
+    } else {
+        read_source_hello(stream).await?
+    };
+    let hello = match source::check_hello(line.as_deref(), nonce.as_deref(), Some(peer), auth, metrics) {
+        Ok(hello) => hello,
+        Err(reason) => return Err(refuse(stream, &reason).await),
+    };
+
+    if line.is_some() {
+        stream.write_all(subscription::source_reply(&hello.channel).as_bytes()).await?;
+    }
+    Ok(hello.channel)
+}
+
+/// Function to read a source client's hello line, if it sent one. See `subscription::read_source_hello`.
+async fn read_source_hello(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 || first[0] != SOURCE_HELLO_START {
+        return Ok(None);
+    }
+
+    // Read one byte at a time, so none of the messages following the hello line are consumed.
+    let mut line = Vec::new();
+    while line.last() != Some(&b'\n') {
+        if line.len() as u64 >= MAX_HELLO_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "hello line is incomplete or too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Function to tell a source client why its handshake failed, returning the error to end the session with.
+async fn refuse(stream: &mut TcpStream, reason: &str) -> io::Error {
+    let _ = stream.write_all(subscription::error_reply(reason).as_bytes()).await;
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Function to perform the handshake with a newly connected destination client. See the `subscription` module.
+async fn handshake(stream: &mut TcpStream, relay_versions: &[Version], timeout: Duration) -> io::Result<Subscription> {
+    let mut line = String::new();
+    let mut reader = BufReader::new(AsyncReadExt::take(&mut *stream, MAX_HELLO_LEN));
+    match tokio::time::timeout(timeout, reader.read_line(&mut line)).await {
+        Ok(result) => {
+            result?;
+        }
+        // The client did not send anything before the timeout, so it receives every message.
+        Err(_) if line.is_empty() => return Ok(Subscription::new(relay_versions)),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "hello line is incomplete")),
+    }
+
+    if !line.is_empty() && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hello line is incomplete or too long"));
+    }
+
+    // An empty line means the client closed its sending side without a hello.
+    match subscription::negotiate(Some(line.as_str()).filter(|line| !line.is_empty()), relay_versions) {
+        Ok((subscription, reply)) => {
+            if let Some(reply) = reply {
+                stream.write_all(reply.as_bytes()).await?;
+            }
+            Ok(subscription)
+        }
+        Err(reason) => {
+            let _ = stream.write_all(subscription::error_reply(&reason).as_bytes()).await;
+            Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+    }
+}
+
+/// Task run for each destination client. Writes broadcast messages the destination is subscribed to until a write
+/// fails, the destination lags behind and the overflow policy is to disconnect it, or the relay is shutting down and
+/// every message waiting for the destination has been written.
+async fn write_destination(
+    mut stream: TcpStream,
+    mut receiver: broadcast::Receiver<Relayed>,
+    subscription: Subscription,
+    overflow_policy: OverflowPolicy,
+    mut draining: watch::Receiver<bool>,
+    metrics: &Metrics,
+) {
+    let mut drain = false;
+    loop {
+        let received = if drain {
+            match receiver.try_recv() {
+                Ok(relayed) => Ok(relayed),
+                Err(TryRecvError::Lagged(skipped)) => Err(RecvError::Lagged(skipped)),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        } else {
+            tokio::select! {
+                received = receiver.recv() => received,
+                _ = wait_set(&mut draining) => {
+                    drain = true;
+                    continue;
+                }
+            }
+        };
+        let (channel, message) = match received {
+            Ok(relayed) => relayed,
+            Err(RecvError::Lagged(_)) if overflow_policy == OverflowPolicy::Disconnect => {
+                warn!("destination queue full, disconnecting lagging destination");
+                metrics::add(&metrics.destinations_removed_lagging, 1);
+                break;
+            }
+            Err(RecvError::Lagged(skipped)) => { // The oldest messages have been skipped, carry on with the rest.
+                debug!(skipped, "destination queue full, messages dropped");
+                metrics::add(&metrics.queue_drops, skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !subscription.accepts(&channel, &message) {
+            continue;
+        }
+
+        if let Err(e) = stream.write_all(message.as_bytes()).await {
+            warn!(error = %e, "write to destination failed, removing destination");
+            metrics::add(&metrics.destinations_removed_on_error, 1);
+            break;
+        }
+        metrics::add(&metrics.bytes_out, message.as_bytes().len() as u64);
+    }
+
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,334 @@
+//! Authentication of source clients, so that only approved producers can send messages to the destinations.
+//!
+//! With `token` authentication the source's hello line must include the shared secret, e.g. `CTMP token=<secret>`.
+//! With `hmac` authentication the relay first sends a random challenge, `CTMP CHALLENGE <nonce>`, and the source must
+//! answer with the HMAC-SHA256 of the nonce's bytes keyed with the shared secret: `CTMP auth=<hmac>`. Both the nonce and
+//! the HMAC are hex encoded. The secret itself is never sent, so it cannot be read off the wire even without TLS.
+//!
+//! Failed attempts are counted per client IP address, and forgotten once an address has not failed for the block
+//! period. Once an address reaches the maximum number of failures it is turned away without a handshake for the whole
+//! block period, after which it starts again with no failures.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::Config;
+
+/// Length of the challenge nonce in bytes.
+const NONCE_LEN: usize = 32;
+
+/// How source clients prove they are allowed to send messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceAuth {
+    /// Any source client is accepted.
+    None,
+    /// The source sends the shared secret in its hello line.
+    Token,
+    /// The source answers a challenge with an HMAC keyed with the shared secret.
+    Hmac,
+}
+
+impl FromStr for SourceAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SourceAuth, String> {
+        match s {
+            "none" => Ok(SourceAuth::None),
+            "token" => Ok(SourceAuth::Token),
+            "hmac" => Ok(SourceAuth::Hmac),
+            _ => Err(format!("unknown source authentication '{}', expected none, token or hmac", s)),
+        }
+    }
+}
+
+impl fmt::Display for SourceAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceAuth::None => "none",
+            SourceAuth::Token => "token",
+            SourceAuth::Hmac => "hmac",
+        })
+    }
+}
+
+/// Failed attempts from a single IP address.
+struct Failures {
+    count: u32,
+    /// When the last failure was. Failures are forgotten once the block period has passed since then.
+    last: Instant,
+    /// Set when the address reached the maximum number of failures, to when it stops being blocked.
+    blocked_until: Option<Instant>,
+}
+
+/// Checks the credentials of source clients and keeps track of failed attempts.
+pub struct Authenticator {
+    method: SourceAuth,
+    key: hmac::Key,
+    /// HMAC of the secret, compared against the HMAC of a token so that the comparison takes constant time.
+    token_tag: hmac::Tag,
+    random: SystemRandom,
+    timeout: Duration,
+    max_failures: u32,
+    block_period: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl Authenticator {
+    /// Sets up source authentication as configured, reading the shared secret from its file.
+    pub fn new(config: &Config) -> io::Result<Authenticator> {
+        let secret = match (&config.source_auth_secret_file, config.source_auth) {
+            (_, SourceAuth::None) => String::new(),
+            (Some(path), _) => {
+                let secret = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("could not read {}: {}", path.display(), e)))?;
+                // Trailing newlines are ignored, as most editors add one.
+                let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+                if secret.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("source authentication secret in {} is empty", path.display())));
+                }
+                secret
+            }
+            (None, _) => unreachable!("rejected when validating the config"),
+        };
+        Ok(Authenticator::with_secret(config, &secret))
+    }
+
+    /// Sets up source authentication as configured, with the given shared secret.
+    fn with_secret(config: &Config, secret: &str) -> Authenticator {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let token_tag = hmac::sign(&key, secret.as_bytes());
+        Authenticator {
+            method: config.source_auth,
+            key,
+            token_tag,
+            random: SystemRandom::new(),
+            timeout: Duration::from_millis(config.source_auth_timeout_ms),
+            max_failures: config.source_auth_max_failures,
+            block_period: Duration::from_secs(config.source_auth_block_secs),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn method(&self) -> SourceAuth {
+        self.method
+    }
+
+    /// How long a source client has to complete the handshake when authentication is enabled.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Creates the challenge to send to a newly connected source client, if HMAC authentication is enabled.
+    pub fn challenge(&self) -> io::Result<Option<Vec<u8>>> {
+        if self.method != SourceAuth::Hmac {
+            return Ok(None);
+        }
+        let mut nonce = vec![0u8; NONCE_LEN];
+        self.random.fill(&mut nonce).map_err(|_| io::Error::other("could not generate authentication challenge"))?;
+        Ok(Some(nonce))
+    }
+
+    /// Checks the credentials given in a source client's hello against the challenge it was sent, if any.
+    pub fn verify(&self, nonce: Option<&[u8]>, token: Option<&str>, response: Option<&str>) -> Result<(), &'static str> {
+        match self.method {
+            SourceAuth::None => Ok(()),
+            SourceAuth::Token => {
+                let token = token.ok_or("token required")?;
+                hmac::verify(&self.key, token.as_bytes(), self.token_tag.as_ref()).map_err(|_| "authentication failed")
+            }
+            SourceAuth::Hmac => {
+                let response = response.and_then(decode_hex).ok_or("hex encoded auth response required")?;
+                let nonce = nonce.ok_or("authentication failed")?;
+                hmac::verify(&self.key, nonce, &response).map_err(|_| "authentication failed")
+            }
+        }
+    }
+
+    /// Returns true if an address has failed to authenticate too many times and is still blocked.
+    pub fn is_blocked(&self, addr: IpAddr) -> bool {
+        self.is_blocked_at(addr, Instant::now())
+    }
+
+    fn is_blocked_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures.get(&addr).and_then(|failures| failures.blocked_until).is_some_and(|until| now < until)
+    }
+
+    /// Records a failed attempt from an address. Returns true if the address is now blocked.
+    pub fn record_failure(&self, addr: IpAddr) -> bool {
+        self.record_failure_at(addr, Instant::now())
+    }
+
+    fn record_failure_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        // Forget addresses that are no longer blocked and have not failed recently, so the map does not grow without
+        // bound.
+        let block_period = self.block_period;
+        failures.retain(|_, failures| failures.blocked_until.is_some_and(|until| now < until) || now.duration_since(failures.last) < block_period);
+
+        let entry = failures.entry(addr).or_insert(Failures { count: 0, last: now, blocked_until: None });
+        if entry.blocked_until.is_some_and(|until| now < until) {
+            return true;
+        }
+        // An address whose block has ended starts again with no failures.
+        if entry.blocked_until.take().is_some() {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        if entry.count >= self.max_failures {
+            entry.blocked_until = Some(now + self.block_period);
+        }
+        entry.blocked_until.is_some()
+    }
+
+    /// Forgets the failed attempts from an address once it has authenticated.
+    pub fn record_success(&self, addr: IpAddr) {
+        self.failures.lock().unwrap().remove(&addr);
+    }
+}
+
+/// Challenge line sent to a source client for HMAC authentication.
+pub fn challenge_line(nonce: &[u8]) -> String {
+    format!("CTMP CHALLENGE {}\n", encode_hex(nonce))
+}
+
+/// Function to hex encode bytes.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Function to decode a hex string, returning `None` if it is not valid hex.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    fn authenticator(method: SourceAuth) -> Authenticator {
+        let config = Config { source_auth: method, source_auth_max_failures: 3, source_auth_block_secs: 60, ..Config::default() };
+        Authenticator::with_secret(&config, SECRET)
+    }
+
+    fn addr() -> IpAddr {
+        IpAddr::from([192, 0, 2, 1])
+    }
+
+    #[test]
+    fn blocked_after_max_failures() {
+        let auth = authenticator(SourceAuth::Token);
+        let now = Instant::now();
+        assert!(!auth.record_failure_at(addr(), now));
+        assert!(!auth.record_failure_at(addr(), now));
+        assert!(!auth.is_blocked_at(addr(), now));
+        assert!(auth.record_failure_at(addr(), now));
+        assert!(auth.is_blocked_at(addr(), now));
+        // Other addresses are not affected.
+        assert!(!auth.is_blocked_at(IpAddr::from([192, 0, 2, 2]), now));
+    }
+
+    #[test]
+    fn block_lasts_the_whole_period_from_the_last_failure() {
+        let auth = authenticator(SourceAuth::Token);
+        let start = Instant::now();
+        // Failures spread over nearly the block period still block the address for the whole period.
+        auth.record_failure_at(addr(), start);
+        auth.record_failure_at(addr(), start + Duration::from_secs(30));
+        assert!(auth.record_failure_at(addr(), start + Duration::from_secs(59)));
+        assert!(auth.is_blocked_at(addr(), start + Duration::from_secs(118)));
+        assert!(!auth.is_blocked_at(addr(), start + Duration::from_secs(119)));
+    }
+
+    #[test]
+    fn failures_start_again_after_block_expires() {
+        let auth = authenticator(SourceAuth::Token);
+        let start = Instant::now();
+        for _ in 0..3 {
+            auth.record_failure_at(addr(), start);
+        }
+        let expired = start + Duration::from_secs(60);
+        assert!(!auth.is_blocked_at(addr(), expired));
+        assert!(!auth.record_failure_at(addr(), expired));
+        assert!(!auth.is_blocked_at(addr(), expired));
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let auth = authenticator(SourceAuth::Token);
+        let start = Instant::now();
+        auth.record_failure_at(addr(), start);
+        auth.record_failure_at(addr(), start);
+        assert!(!auth.record_failure_at(addr(), start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let auth = authenticator(SourceAuth::Token);
+        let now = Instant::now();
+        auth.record_failure_at(addr(), now);
+        auth.record_failure_at(addr(), now);
+        auth.record_success(addr());
+        assert!(!auth.record_failure_at(addr(), now));
+    }
+
+    #[test]
+    fn verifies_token() {
+        let auth = authenticator(SourceAuth::Token);
+        assert_eq!(auth.verify(None, Some(SECRET), None), Ok(()));
+        assert_eq!(auth.verify(None, Some("wrong"), None), Err("authentication failed"));
+        assert_eq!(auth.verify(None, None, None), Err("token required"));
+    }
+
+    #[test]
+    fn verifies_hmac_response() {
+        let auth = authenticator(SourceAuth::Hmac);
+        let nonce = auth.challenge().unwrap().unwrap();
+        assert_eq!(nonce.len(), NONCE_LEN);
+        assert!(challenge_line(&nonce).starts_with("CTMP CHALLENGE "));
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+        let response = encode_hex(hmac::sign(&key, &nonce).as_ref());
+        assert_eq!(auth.verify(Some(&nonce), None, Some(&response)), Ok(()));
+
+        // A response to another nonce, a response made with another secret and a response that is not hex all fail.
+        let other_nonce = auth.challenge().unwrap().unwrap();
+        assert_eq!(auth.verify(Some(&other_nonce), None, Some(&response)), Err("authentication failed"));
+        let wrong_key = hmac::Key::new(hmac::HMAC_SHA256, b"wrong");
+        let wrong = encode_hex(hmac::sign(&wrong_key, &nonce).as_ref());
+        assert_eq!(auth.verify(Some(&nonce), None, Some(&wrong)), Err("authentication failed"));
+        assert_eq!(auth.verify(Some(&nonce), None, Some("xyz")), Err("hex encoded auth response required"));
+        assert_eq!(auth.verify(Some(&nonce), None, None), Err("hex encoded auth response required"));
+        assert_eq!(auth.verify(None, None, Some(&response)), Err("authentication failed"));
+    }
+
+    #[test]
+    fn no_authentication_accepts_anything() {
+        let auth = authenticator(SourceAuth::None);
+        assert!(auth.challenge().unwrap().is_none());
+        assert_eq!(auth.verify(None, None, None), Ok(()));
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff7F"), Some(vec![0x00, 0xFF, 0x7F]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+    }
+}
@@ -0,0 +1,94 @@
+//! Command line client for the relay's admin socket. Sends a single command and prints its output.
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: ctmp-admin [--socket <PATH>] <COMMAND> [ARGUMENT]
+
+Commands:
+  list-destinations       List the connected destination clients
+  show-source             Show the source clients being relayed
+  kick <id>               Disconnect a destination client
+  stats                   Show the metrics
+  set-log-level <filter>  Change the log level, e.g. debug or warn,tcp_server::source=debug
+  help                    List the commands the relay supports
+
+Options:
+  --socket <PATH>         Admin socket of the relay, as set with its --admin-socket option
+                          (default $CTMP_ADMIN_SOCKET)
+  -h, --help              Print this help
+";
+
+fn main() -> ExitCode {
+    let mut socket = env::var_os("CTMP_ADMIN_SOCKET").map(PathBuf::from);
+    let mut command: Vec<String> = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            "--socket" => match args.next() {
+                Some(path) => socket = Some(PathBuf::from(path)),
+                None => return usage_error("missing value for --socket"),
+            },
+            _ => match arg.strip_prefix("--socket=") {
+                Some(path) => socket = Some(PathBuf::from(path)),
+                None => command.push(arg),
+            },
+        }
+    }
+
+    let Some(socket) = socket else {
+        return usage_error("no admin socket given, use --socket or set CTMP_ADMIN_SOCKET");
+    };
+    if command.is_empty() {
+        return usage_error("no command given");
+    }
+
+    match send(&socket, &command.join(" ")) {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(reason)) => {
+            eprintln!("error: {}", reason);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("error: could not talk to the relay at {}: {}", socket.display(), e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Function to report a problem with the command line, and return the exit code for it.
+fn usage_error(reason: &str) -> ExitCode {
+    eprintln!("error: {}\n\n{}", reason, USAGE);
+    ExitCode::from(2)
+}
+
+/// Function to send a command to the relay, printing its output. Returns the reason the relay gave if the command
+/// failed.
+fn send(socket: &Path, command: &str) -> io::Result<Result<(), String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    // The output is followed by a line saying whether the command succeeded.
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "OK" {
+            return Ok(Ok(()));
+        }
+        if let Some(reason) = line.strip_prefix("ERR ") {
+            return Ok(Err(reason.to_string()));
+        }
+        writeln!(stdout, "{}", line)?;
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the relay closed the connection without replying"))
+}
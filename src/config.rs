@@ -0,0 +1,853 @@
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ctmp::{HEADER_LEN, Validation, Version};
+use serde::{Deserialize, Deserializer};
+
+use crate::acl::Cidr;
+use crate::arbiter::Fairness;
+use crate::auth::SourceAuth;
+use crate::destination::OverflowPolicy;
+use crate::journal::FsyncPolicy;
+use crate::logging::{self, LogFormat};
+use crate::multicast::MulticastInterface;
+use crate::source::SourceConflict;
+use crate::transport::{ListenAddr, SocketMode};
+use crate::websocket::WsFormat;
+
+const USAGE: &str = "\
+Usage: tcp-server [OPTIONS]
+
+Options:
+  --config <PATH>              Load settings from a TOML config file. Options given on the command line override it.
+  --source-addr <ADDRS>        Addresses to accept source clients on, comma separated: host:port for TCP or
+                               unix:<path> for a Unix domain socket (default 0.0.0.0:33333)
+  --source-mode <MODE>         single (one source at a time) or multi (concurrent sources) (default single)
+  --source-conflict <POLICY>   What to do when a source connects in single mode while another is connected:
+                               reject, preempt or queue (default queue)
+  --source-wait-timeout-ms <MS> How long a queued source waits for the current source to disconnect (default 5000)
+  --source-auth <METHOD>       Source authentication: none, token or hmac (default none)
+  --source-auth-secret-file <PATH>
+                               File containing the shared secret for token or hmac authentication
+  --source-auth-timeout-ms <MS> How long a source has to authenticate (default 5000)
+  --source-auth-max-failures <N>
+                               Failed attempts from an IP address before it is blocked (default 5)
+  --source-auth-block-secs <SECS>
+                               How long an IP address stays blocked after too many failures (default 60)
+  --source-allow <CIDRS>       Only accept source clients from these addresses, e.g. 10.0.0.0/8,::1 (default any)
+  --source-deny <CIDRS>        Refuse source clients from these addresses, even if allowed (default none)
+  --max-sources <N>            Maximum number of concurrent source clients in multi mode (default unlimited)
+  --source-queue-depth <N>     Messages queued per source in multi mode before reading from it pauses (default 64)
+  --fairness <POLICY>          round-robin or byte-fair arbitration between sources in multi mode (default round-robin)
+  --dest-addr <ADDRS>          Addresses to accept destination clients on, as for --source-addr (default 0.0.0.0:44444)
+  --ws-addr <ADDRS>            Also accept destination clients over WebSocket on these addresses, as for --dest-addr
+                               (default disabled)
+  --ws-format <FORMAT>         binary or json: how messages are sent to WebSocket clients that do not ask for a
+                               format (default binary)
+  --unix-socket-mode <MODE>    Octal permissions of the Unix domain sockets listened on (default 660)
+  --dest-allow <CIDRS>         Only accept destination clients from these addresses (default any)
+  --dest-deny <CIDRS>          Refuse destination clients from these addresses, even if allowed (default none)
+  --max-destinations <N>       Maximum number of connected destination clients (default unlimited)
+  --max-payload <BYTES>        Maximum accepted payload length, up to 65535 (default 65535)
+  --max-buffer <BYTES>         Maximum bytes buffered from the source, at least max-payload + 8 (default 131072)
+  --dest-queue-depth <N>       Messages queued per destination before the overflow policy applies (default 1024)
+  --dest-overflow <POLICY>     drop-oldest, drop-newest or disconnect (default drop-oldest)
+  --validation <MODE>          lenient or strict checking of reserved header fields (default lenient)
+  --source-versions <LIST>     CTMP versions accepted from the source, e.g. 1,2 (default 1,2)
+  --dest-hello-timeout-ms <MS> How long to wait for a destination client's optional hello line (default 100)
+  --history-messages <N>       Keep the last N messages to replay to new destinations, at most dest-queue-depth
+                               (default 0, disabled)
+  --history-secs <SECS>        Only replay messages received in the last SECS seconds (default unlimited)
+  --source-tls-cert <PATH>     Accept source clients over TLS with this PEM certificate chain (default plaintext)
+  --source-tls-key <PATH>      PEM private key for --source-tls-cert
+  --dest-tls-cert <PATH>       Accept destination clients over TLS with this PEM certificate chain (default plaintext)
+  --dest-tls-key <PATH>        PEM private key for --dest-tls-cert
+  --journal-dir <PATH>         Append every accepted message to segment files in this directory (default disabled)
+  --journal-fsync <POLICY>     always, interval or never: when journal writes are flushed to disk (default interval)
+  --journal-fsync-interval-ms <MS>
+                               How often journal writes are flushed with the interval policy (default 1000)
+  --journal-segment-bytes <BYTES>
+                               Start a new journal segment once the current one reaches this size (default 67108864)
+  --journal-segment-secs <SECS>
+                               Start a new journal segment once the current one is this old (default unlimited)
+  --journal-retention-bytes <BYTES>
+                               Delete the oldest journal segments while the journal is larger (default unlimited)
+  --journal-retention-secs <SECS>
+                               Delete journal segments whose newest message is older (default unlimited)
+  --multicast-group <ADDR>     Also publish every relayed message to this UDP multicast group, e.g. 239.1.2.3:5000
+                               (default disabled)
+  --multicast-ttl <N>          TTL (IPv4) or hop limit (IPv6) of multicast datagrams (default 1, the local network)
+  --multicast-interface <IF>   Interface to send multicast datagrams from: its IPv4 address for an IPv4 group, or its
+                               index for an IPv6 group (default chosen by the operating system)
+  --shutdown-timeout-ms <MS>   How long to spend relaying queued messages on SIGINT or SIGTERM before exiting
+                               (default 5000)
+  --metrics-addr <ADDR>        Serve Prometheus metrics on http://ADDR/metrics (default disabled)
+  --admin-socket <PATH>        Serve admin commands for ctmp-admin on a Unix domain socket at PATH (default disabled)
+  --log-level <FILTER>         Log verbosity: error, warn, info, debug or trace, or a filter such as
+                               \"warn,tcp_server=debug\" (default info)
+  --log-format <FORMAT>        text or json (default text)
+  --runtime <RUNTIME>          threaded or async (default threaded, async requires the async feature)
+  -h, --help                   Print this help
+";
+
+/// Command line options that set a config value, handled by `Config::set`.
+const OPTIONS: &[&str] = &[
+    "--source-addr",
+    "--source-mode",
+    "--source-conflict",
+    "--source-wait-timeout-ms",
+    "--source-auth",
+    "--source-auth-secret-file",
+    "--source-auth-timeout-ms",
+    "--source-auth-max-failures",
+    "--source-auth-block-secs",
+    "--source-allow",
+    "--source-deny",
+    "--max-sources",
+    "--source-queue-depth",
+    "--fairness",
+    "--dest-addr",
+    "--ws-addr",
+    "--ws-format",
+    "--unix-socket-mode",
+    "--dest-allow",
+    "--dest-deny",
+    "--max-destinations",
+    "--max-payload",
+    "--max-buffer",
+    "--dest-queue-depth",
+    "--dest-overflow",
+    "--validation",
+    "--source-versions",
+    "--dest-hello-timeout-ms",
+    "--history-messages",
+    "--history-secs",
+    "--source-tls-cert",
+    "--source-tls-key",
+    "--dest-tls-cert",
+    "--dest-tls-key",
+    "--journal-dir",
+    "--journal-fsync",
+    "--journal-fsync-interval-ms",
+    "--journal-segment-bytes",
+    "--journal-segment-secs",
+    "--journal-retention-bytes",
+    "--journal-retention-secs",
+    "--multicast-group",
+    "--multicast-ttl",
+    "--multicast-interface",
+    "--shutdown-timeout-ms",
+    "--metrics-addr",
+    "--admin-socket",
+    "--log-level",
+    "--log-format",
+    "--runtime",
+];
+
+/// Which implementation of the relay to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    Threaded,
+    Async,
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Runtime, String> {
+        match s {
+            "threaded" => Ok(Runtime::Threaded),
+            "async" => Ok(Runtime::Async),
+            _ => Err(format!("unknown runtime '{}', expected threaded or async", s)),
+        }
+    }
+}
+
+/// Whether source clients are served one at a time or concurrently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceMode {
+    Single,
+    Multi,
+}
+
+impl FromStr for SourceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SourceMode, String> {
+        match s {
+            "single" => Ok(SourceMode::Single),
+            "multi" => Ok(SourceMode::Multi),
+            _ => Err(format!("unknown source mode '{}', expected single or multi", s)),
+        }
+    }
+}
+
+impl fmt::Display for SourceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceMode::Single => "single",
+            SourceMode::Multi => "multi",
+        })
+    }
+}
+
+/// Settings of the relay, loaded from the command line and an optional config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_addrs")]
+    pub source_addr: Vec<ListenAddr>,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub source_mode: SourceMode,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub source_conflict: SourceConflict,
+    pub source_wait_timeout_ms: u64,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub source_auth: SourceAuth,
+    pub source_auth_secret_file: Option<PathBuf>,
+    pub source_auth_timeout_ms: u64,
+    pub source_auth_max_failures: u32,
+    pub source_auth_block_secs: u64,
+    #[serde(deserialize_with = "deserialize_list")]
+    pub source_allow: Vec<Cidr>,
+    #[serde(deserialize_with = "deserialize_list")]
+    pub source_deny: Vec<Cidr>,
+    pub max_sources: Option<usize>,
+    pub source_queue_depth: usize,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub fairness: Fairness,
+    #[serde(deserialize_with = "deserialize_addrs")]
+    pub dest_addr: Vec<ListenAddr>,
+    #[serde(deserialize_with = "deserialize_addrs")]
+    pub ws_addr: Vec<ListenAddr>,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub ws_format: WsFormat,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub unix_socket_mode: SocketMode,
+    #[serde(deserialize_with = "deserialize_list")]
+    pub dest_allow: Vec<Cidr>,
+    #[serde(deserialize_with = "deserialize_list")]
+    pub dest_deny: Vec<Cidr>,
+    pub max_destinations: Option<usize>,
+    pub max_payload: usize,
+    pub max_buffer: usize,
+    pub dest_queue_depth: usize,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub dest_overflow: OverflowPolicy,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub validation: Validation,
+    #[serde(deserialize_with = "deserialize_versions")]
+    pub source_versions: Vec<Version>,
+    pub dest_hello_timeout_ms: u64,
+    pub history_messages: usize,
+    pub history_secs: Option<u64>,
+    pub source_tls_cert: Option<PathBuf>,
+    pub source_tls_key: Option<PathBuf>,
+    pub dest_tls_cert: Option<PathBuf>,
+    pub dest_tls_key: Option<PathBuf>,
+    pub journal_dir: Option<PathBuf>,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub journal_fsync: FsyncPolicy,
+    pub journal_fsync_interval_ms: u64,
+    pub journal_segment_bytes: u64,
+    pub journal_segment_secs: Option<u64>,
+    pub journal_retention_bytes: Option<u64>,
+    pub journal_retention_secs: Option<u64>,
+    pub multicast_group: Option<SocketAddr>,
+    pub multicast_ttl: u32,
+    #[serde(default, deserialize_with = "deserialize_option_from_str")]
+    pub multicast_interface: Option<MulticastInterface>,
+    pub shutdown_timeout_ms: u64,
+    pub metrics_addr: Option<SocketAddr>,
+    pub admin_socket: Option<PathBuf>,
+    pub log_level: String,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub log_format: LogFormat,
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub runtime: Runtime,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            source_addr: vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 33333)))],
+            source_mode: SourceMode::Single,
+            source_conflict: SourceConflict::Queue,
+            source_wait_timeout_ms: 5000,
+            source_auth: SourceAuth::None,
+            source_auth_secret_file: None,
+            source_auth_timeout_ms: 5000,
+            source_auth_max_failures: 5,
+            source_auth_block_secs: 60,
+            source_allow: Vec::new(),
+            source_deny: Vec::new(),
+            max_sources: None,
+            source_queue_depth: 64,
+            fairness: Fairness::RoundRobin,
+            dest_addr: vec![ListenAddr::Tcp(SocketAddr::from(([0, 0, 0, 0], 44444)))],
+            ws_addr: Vec::new(),
+            ws_format: WsFormat::Binary,
+            unix_socket_mode: SocketMode(0o660),
+            dest_allow: Vec::new(),
+            dest_deny: Vec::new(),
+            max_destinations: None,
+            max_payload: u16::MAX as usize,
+            max_buffer: 128 * 1024,
+            dest_queue_depth: 1024,
+            dest_overflow: OverflowPolicy::DropOldest,
+            validation: Validation::Lenient,
+            source_versions: Version::ALL.to_vec(),
+            dest_hello_timeout_ms: 100,
+            history_messages: 0,
+            history_secs: None,
+            source_tls_cert: None,
+            source_tls_key: None,
+            dest_tls_cert: None,
+            dest_tls_key: None,
+            journal_dir: None,
+            journal_fsync: FsyncPolicy::Interval,
+            journal_fsync_interval_ms: 1000,
+            journal_segment_bytes: 64 * 1024 * 1024,
+            journal_segment_secs: None,
+            journal_retention_bytes: None,
+            journal_retention_secs: None,
+            multicast_group: None,
+            multicast_ttl: 1,
+            multicast_interface: None,
+            shutdown_timeout_ms: 5000,
+            metrics_addr: None,
+            admin_socket: None,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            runtime: Runtime::Threaded,
+        }
+    }
+}
+
+/// Errors that prevent the relay from starting with the given settings.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given, so the usage should be printed instead of starting.
+    Help,
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidValue { option: String, value: String, reason: String },
+    ReadFile { path: PathBuf, error: std::io::Error },
+    ParseFile { path: PathBuf, error: toml::de::Error },
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Help => f.write_str(USAGE),
+            ConfigError::UnknownArgument(arg) => write!(f, "unknown argument '{}'\n\n{}", arg, USAGE),
+            ConfigError::MissingValue(option) => write!(f, "missing value for {}", option),
+            ConfigError::InvalidValue { option, value, reason } => write!(f, "invalid value '{}' for {}: {}", value, option, reason),
+            ConfigError::ReadFile { path, error } => write!(f, "could not read config file {}: {}", path.display(), error),
+            ConfigError::ParseFile { path, error } => write!(f, "could not parse config file {}: {}", path.display(), error),
+            ConfigError::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the settings from the given command line arguments (excluding the program name).
+    /// If `--config` is given the file is loaded first, and any other options given override its settings.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+        // Pairs of option name and value, applied once the config file (if any) has been loaded.
+        let mut options: Vec<(String, String)> = Vec::new();
+        let mut config_path: Option<PathBuf> = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(ConfigError::Help);
+            }
+            if !arg.starts_with("--") {
+                return Err(ConfigError::UnknownArgument(arg));
+            }
+
+            // Options can be given as either "--option value" or "--option=value".
+            let (option, value) = match arg.split_once('=') {
+                Some((option, value)) => (option.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if option != "--config" && !OPTIONS.contains(&option.as_str()) {
+                return Err(ConfigError::UnknownArgument(option));
+            }
+            let value = match value {
+                Some(value) => value,
+                None => args.next().ok_or_else(|| ConfigError::MissingValue(option.clone()))?,
+            };
+
+            if option == "--config" {
+                config_path = Some(PathBuf::from(value));
+            } else {
+                options.push((option, value));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        for (option, value) in options {
+            config.set(&option, &value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Loads the settings from a TOML config file. Settings missing from the file keep their default values.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|error| ConfigError::ReadFile { path: path.to_path_buf(), error })?;
+        toml::from_str(&contents).map_err(|error| ConfigError::ParseFile { path: path.to_path_buf(), error })
+    }
+
+    /// Applies a single command line option.
+    fn set(&mut self, option: &str, value: &str) -> Result<(), ConfigError> {
+        match option {
+            "--source-addr" => self.source_addr = parse_list(option, value)?,
+            "--source-mode" => self.source_mode = parse(option, value)?,
+            "--source-conflict" => self.source_conflict = parse(option, value)?,
+            "--source-wait-timeout-ms" => self.source_wait_timeout_ms = parse(option, value)?,
+            "--source-auth" => self.source_auth = parse(option, value)?,
+            "--source-auth-secret-file" => self.source_auth_secret_file = Some(PathBuf::from(value)),
+            "--source-auth-timeout-ms" => self.source_auth_timeout_ms = parse(option, value)?,
+            "--source-auth-max-failures" => self.source_auth_max_failures = parse(option, value)?,
+            "--source-auth-block-secs" => self.source_auth_block_secs = parse(option, value)?,
+            "--source-allow" => self.source_allow = parse_list(option, value)?,
+            "--source-deny" => self.source_deny = parse_list(option, value)?,
+            "--max-sources" => self.max_sources = Some(parse(option, value)?),
+            "--source-queue-depth" => self.source_queue_depth = parse(option, value)?,
+            "--fairness" => self.fairness = parse(option, value)?,
+            "--dest-addr" => self.dest_addr = parse_list(option, value)?,
+            "--ws-addr" => self.ws_addr = parse_list(option, value)?,
+            "--ws-format" => self.ws_format = parse(option, value)?,
+            "--unix-socket-mode" => self.unix_socket_mode = parse(option, value)?,
+            "--dest-allow" => self.dest_allow = parse_list(option, value)?,
+            "--dest-deny" => self.dest_deny = parse_list(option, value)?,
+            "--max-destinations" => self.max_destinations = Some(parse(option, value)?),
+            "--max-payload" => self.max_payload = parse(option, value)?,
+            "--max-buffer" => self.max_buffer = parse(option, value)?,
+            "--dest-queue-depth" => self.dest_queue_depth = parse(option, value)?,
+            "--dest-overflow" => self.dest_overflow = parse(option, value)?,
+            "--validation" => self.validation = parse(option, value)?,
+            "--source-versions" => self.source_versions = value.split(',').map(|v| parse(option, v)).collect::<Result<_, _>>()?,
+            "--dest-hello-timeout-ms" => self.dest_hello_timeout_ms = parse(option, value)?,
+            "--history-messages" => self.history_messages = parse(option, value)?,
+            "--history-secs" => self.history_secs = Some(parse(option, value)?),
+            "--source-tls-cert" => self.source_tls_cert = Some(PathBuf::from(value)),
+            "--source-tls-key" => self.source_tls_key = Some(PathBuf::from(value)),
+            "--dest-tls-cert" => self.dest_tls_cert = Some(PathBuf::from(value)),
+            "--dest-tls-key" => self.dest_tls_key = Some(PathBuf::from(value)),
+            "--journal-dir" => self.journal_dir = Some(PathBuf::from(value)),
+            "--journal-fsync" => self.journal_fsync = parse(option, value)?,
+            "--journal-fsync-interval-ms" => self.journal_fsync_interval_ms = parse(option, value)?,
+            "--journal-segment-bytes" => self.journal_segment_bytes = parse(option, value)?,
+            "--journal-segment-secs" => self.journal_segment_secs = Some(parse(option, value)?),
+            "--journal-retention-bytes" => self.journal_retention_bytes = Some(parse(option, value)?),
+            "--journal-retention-secs" => self.journal_retention_secs = Some(parse(option, value)?),
+            "--multicast-group" => self.multicast_group = Some(parse(option, value)?),
+            "--multicast-ttl" => self.multicast_ttl = parse(option, value)?,
+            "--multicast-interface" => self.multicast_interface = Some(parse(option, value)?),
+            "--shutdown-timeout-ms" => self.shutdown_timeout_ms = parse(option, value)?,
+            "--metrics-addr" => self.metrics_addr = Some(parse(option, value)?),
+            "--admin-socket" => self.admin_socket = Some(PathBuf::from(value)),
+            "--log-level" => self.log_level = value.to_string(),
+            "--log-format" => self.log_format = parse(option, value)?,
+            "--runtime" => self.runtime = parse(option, value)?,
+            _ => return Err(ConfigError::UnknownArgument(option.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Checks that the settings are consistent with each other and usable.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.source_addr.is_empty() || self.dest_addr.is_empty() {
+            return Err(ConfigError::Invalid("source_addr and dest_addr must each have at least one address".to_string()));
+        }
+        let listen_addrs: Vec<&ListenAddr> = self.source_addr.iter().chain(&self.dest_addr).chain(&self.ws_addr).collect();
+        for (i, addr) in listen_addrs.iter().enumerate() {
+            if listen_addrs[..i].contains(addr) {
+                return Err(ConfigError::Invalid(format!("{} is listed more than once in source_addr, dest_addr and ws_addr", addr)));
+            }
+        }
+        if self.metrics_addr.is_some_and(|addr| listen_addrs.contains(&&ListenAddr::Tcp(addr))) {
+            return Err(ConfigError::Invalid("metrics_addr must be different from source_addr, dest_addr and ws_addr".to_string()));
+        }
+        if let Some(admin_socket) = &self.admin_socket
+            && listen_addrs.contains(&&ListenAddr::Unix(admin_socket.clone()))
+        {
+            return Err(ConfigError::Invalid("admin_socket must be different from the Unix domain sockets in source_addr, dest_addr and ws_addr".to_string()));
+        }
+        if self.source_auth != SourceAuth::None && self.source_auth_secret_file.is_none() {
+            return Err(ConfigError::Invalid(format!("source_auth_secret_file must be set for {} source authentication", self.source_auth)));
+        }
+        if self.source_auth_max_failures == 0 {
+            return Err(ConfigError::Invalid("source_auth_max_failures must be at least 1".to_string()));
+        }
+        if self.max_sources == Some(0) {
+            return Err(ConfigError::Invalid("max_sources must be at least 1".to_string()));
+        }
+        if self.source_queue_depth == 0 {
+            return Err(ConfigError::Invalid("source_queue_depth must be at least 1".to_string()));
+        }
+        if self.max_destinations == Some(0) {
+            return Err(ConfigError::Invalid("max_destinations must be at least 1".to_string()));
+        }
+        if self.max_payload > u16::MAX as usize {
+            return Err(ConfigError::Invalid(format!("max_payload must be at most {}, got {}", u16::MAX, self.max_payload)));
+        }
+        if self.max_buffer < HEADER_LEN + self.max_payload {
+            return Err(ConfigError::Invalid(format!(
+                "max_buffer must be at least max_payload + {} ({}) to fit a whole message, got {}",
+                HEADER_LEN,
+                HEADER_LEN + self.max_payload,
+                self.max_buffer
+            )));
+        }
+        if self.dest_queue_depth == 0 {
+            return Err(ConfigError::Invalid("dest_queue_depth must be at least 1".to_string()));
+        }
+        if self.history_messages > self.dest_queue_depth {
+            return Err(ConfigError::Invalid(format!(
+                "history_messages must be at most dest_queue_depth ({}) so the history fits in a destination's queue, got {}",
+                self.dest_queue_depth, self.history_messages
+            )));
+        }
+        if self.history_secs.is_some() && self.history_messages == 0 {
+            return Err(ConfigError::Invalid("history_secs requires history_messages to be set".to_string()));
+        }
+        if self.source_versions.is_empty() {
+            return Err(ConfigError::Invalid("source_versions must contain at least one version".to_string()));
+        }
+        if self.source_tls_cert.is_some() != self.source_tls_key.is_some() {
+            return Err(ConfigError::Invalid("source_tls_cert and source_tls_key must be set together".to_string()));
+        }
+        if self.dest_tls_cert.is_some() != self.dest_tls_key.is_some() {
+            return Err(ConfigError::Invalid("dest_tls_cert and dest_tls_key must be set together".to_string()));
+        }
+        if self.journal_segment_bytes == 0 {
+            return Err(ConfigError::Invalid("journal_segment_bytes must be at least 1".to_string()));
+        }
+        if self.journal_fsync == FsyncPolicy::Interval && self.journal_fsync_interval_ms == 0 {
+            return Err(ConfigError::Invalid("journal_fsync_interval_ms must be at least 1".to_string()));
+        }
+        if self.journal_segment_secs == Some(0) {
+            return Err(ConfigError::Invalid("journal_segment_secs must be at least 1".to_string()));
+        }
+        if let Some(group) = self.multicast_group {
+            if !group.ip().is_multicast() {
+                return Err(ConfigError::Invalid(format!("multicast_group must be a multicast address, got {}", group.ip())));
+            }
+            match (group, self.multicast_interface) {
+                (SocketAddr::V4(_), Some(MulticastInterface::Index(_))) => {
+                    return Err(ConfigError::Invalid("multicast_interface must be an IPv4 address for an IPv4 multicast_group".to_string()));
+                }
+                (SocketAddr::V6(_), Some(MulticastInterface::Addr(_))) => {
+                    return Err(ConfigError::Invalid("multicast_interface must be an interface index for an IPv6 multicast_group".to_string()));
+                }
+                _ => {}
+            }
+        } else if self.multicast_interface.is_some() {
+            return Err(ConfigError::Invalid("multicast_interface requires multicast_group to be set".to_string()));
+        }
+        if self.multicast_ttl > 255 {
+            return Err(ConfigError::Invalid(format!("multicast_ttl must be at most 255, got {}", self.multicast_ttl)));
+        }
+        logging::parse_filter(&self.log_level).map_err(ConfigError::Invalid)?;
+        if self.runtime == Runtime::Async {
+            if !cfg!(feature = "async") {
+                return Err(ConfigError::Invalid("the async runtime requires building with the async feature".to_string()));
+            }
+            // The async relay's broadcast channel always discards the oldest messages of a lagging destination.
+            if self.dest_overflow == OverflowPolicy::DropNewest {
+                return Err(ConfigError::Invalid("the async runtime does not support the drop-newest overflow policy".to_string()));
+            }
+            if self.source_mode == SourceMode::Multi {
+                return Err(ConfigError::Invalid("the async runtime does not support the multi source mode".to_string()));
+            }
+            if self.source_tls_cert.is_some() || self.dest_tls_cert.is_some() {
+                return Err(ConfigError::Invalid("the async runtime does not support TLS".to_string()));
+            }
+            if self.history_messages > 0 {
+                return Err(ConfigError::Invalid("the async runtime does not support history replay".to_string()));
+            }
+            if self.journal_dir.is_some() {
+                return Err(ConfigError::Invalid("the async runtime does not support the journal".to_string()));
+            }
+            if self.admin_socket.is_some() {
+                return Err(ConfigError::Invalid("the async runtime does not support the admin socket".to_string()));
+            }
+            if self.multicast_group.is_some() {
+                return Err(ConfigError::Invalid("the async runtime does not support multicast egress".to_string()));
+            }
+            if !self.ws_addr.is_empty() {
+                return Err(ConfigError::Invalid("the async runtime does not support WebSocket destinations".to_string()));
+            }
+            if !matches!(self.source_addr[..], [ListenAddr::Tcp(_)]) || !matches!(self.dest_addr[..], [ListenAddr::Tcp(_)]) {
+                return Err(ConfigError::Invalid("the async runtime only supports a single TCP address for source_addr and dest_addr".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Function to parse a command line option's value, converting any error into a `ConfigError`.
+fn parse<T>(option: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::InvalidValue { option: option.to_string(), value: value.to_string(), reason: e.to_string() })
+}
+
+/// Function to parse a comma separated command line option's values.
+fn parse_list<T>(option: &str, value: &str) -> Result<Vec<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.split(',').map(|v| parse(option, v.trim())).collect()
+}
+
+/// Function to deserialize config file values for types that are parsed from a string.
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+/// Function to deserialize optional config file values for types that are parsed from a string.
+fn deserialize_option_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    deserialize_from_str(deserializer).map(Some)
+}
+
+/// Function to deserialize a list of config file values for types that are parsed from a string.
+fn deserialize_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+    values.iter().map(|value| value.parse().map_err(serde::de::Error::custom)).collect()
+}
+
+/// Function to deserialize listen addresses from a config file, given either as a single address or a list.
+fn deserialize_addrs<'de, D>(deserializer: D) -> Result<Vec<ListenAddr>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Addrs {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match Addrs::deserialize(deserializer)? {
+        Addrs::One(value) => vec![value],
+        Addrs::Many(values) => values,
+    };
+    values.iter().map(|value| value.parse().map_err(serde::de::Error::custom)).collect()
+}
+
+/// Function to deserialize a list of CTMP version numbers from a config file.
+fn deserialize_versions<'de, D>(deserializer: D) -> Result<Vec<Version>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let numbers = Vec::<u8>::deserialize(deserializer)?;
+    numbers.into_iter().map(|number| Version::try_from(number).map_err(serde::de::Error::custom)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    const EXAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/relay.example.toml");
+
+    fn from_args(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Function to load a config file with the given contents and command line arguments.
+    fn from_file_and_args(contents: &str, args: &[&str]) -> Result<Config, ConfigError> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        let path = file.path().to_str().unwrap();
+        from_args(&[&["--config", path], args].concat())
+    }
+
+    /// Function to get the reason the given arguments are refused by validation.
+    fn invalid(args: &[&str]) -> String {
+        match from_args(args) {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("{:?} gave {:?}, expected a validation error", args, other),
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let contents = "dest_queue_depth = 10\nhistory_messages = 5\ndest_overflow = \"disconnect\"\n";
+        let config = from_file_and_args(contents, &["--dest-queue-depth", "20", "--dest-overflow=drop-newest"]).unwrap();
+        assert_eq!(config.dest_queue_depth, 20);
+        assert_eq!(config.dest_overflow, OverflowPolicy::DropNewest);
+        // Settings only in the file are kept, and settings in neither keep their defaults.
+        assert_eq!(config.history_messages, 5);
+        assert_eq!(config.max_payload, Config::default().max_payload);
+
+        // The config file may come after the options overriding it.
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        let config = from_args(&["--dest-queue-depth", "20", "--config", file.path().to_str().unwrap()]).unwrap();
+        assert_eq!(config.dest_queue_depth, 20);
+    }
+
+    #[test]
+    fn validation_applies_after_overrides() {
+        // The file alone is invalid, but the command line fixes it.
+        let contents = "dest_queue_depth = 4\nhistory_messages = 8\n";
+        assert!(matches!(from_file_and_args(contents, &[]), Err(ConfigError::Invalid(_))));
+        assert_eq!(from_file_and_args(contents, &["--history-messages", "4"]).unwrap().history_messages, 4);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_options() {
+        match from_file_and_args("dest_queue_depht = 10\n", &[]) {
+            Err(ConfigError::ParseFile { error, .. }) => assert!(error.to_string().contains("unknown field `dest_queue_depht`"), "{}", error),
+            other => panic!("unknown key gave {:?}", other),
+        }
+        assert!(matches!(from_file_and_args("[dest]\nqueue_depth = 10\n", &[]), Err(ConfigError::ParseFile { .. })));
+        assert!(matches!(from_file_and_args("dest_queue_depth = \"ten\"\n", &[]), Err(ConfigError::ParseFile { .. })));
+
+        assert!(matches!(from_args(&["--dest-queue-depht", "10"]), Err(ConfigError::UnknownArgument(arg)) if arg == "--dest-queue-depht"));
+        assert!(matches!(from_args(&["dest-queue-depth"]), Err(ConfigError::UnknownArgument(_))));
+        assert!(matches!(from_args(&["--dest-queue-depth"]), Err(ConfigError::MissingValue(option)) if option == "--dest-queue-depth"));
+        assert!(matches!(from_args(&["--dest-queue-depth", "ten"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(from_args(&["--config", "/nonexistent/relay.toml"]), Err(ConfigError::ReadFile { .. })));
+        assert!(matches!(from_args(&["--help"]), Err(ConfigError::Help)));
+    }
+
+    #[test]
+    fn example_file_matches_defaults() {
+        let example = Config::from_file(Path::new(EXAMPLE)).unwrap();
+        assert_eq!(format!("{:?}", example), format!("{:?}", Config::default()));
+        from_args(&["--config", EXAMPLE]).unwrap();
+    }
+
+    #[test]
+    fn example_file_documents_valid_keys() {
+        // Every optional setting is given commented out, so uncommenting them all must still give a file that parses.
+        let contents = fs::read_to_string(EXAMPLE).unwrap();
+        let uncommented: String = contents
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.split_once(" = ").is_some_and(|(key, _)| key.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')) => setting,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let config: Config = toml::from_str(&uncommented).unwrap();
+        assert!(config.admin_socket.is_some() && config.journal_dir.is_some() && config.multicast_interface.is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        for (args, reason) in [
+            (&["--source-addr", "127.0.0.1:5000", "--dest-addr", "127.0.0.1:5000"][..], "127.0.0.1:5000 is listed more than once"),
+            (&["--ws-addr", "0.0.0.0:44444"], "0.0.0.0:44444 is listed more than once"),
+            (&["--metrics-addr", "0.0.0.0:44444"], "metrics_addr must be different"),
+            (&["--dest-addr", "unix:/tmp/relay.sock", "--admin-socket", "/tmp/relay.sock"], "admin_socket must be different"),
+            (&["--source-auth", "token"], "source_auth_secret_file must be set for token source authentication"),
+            (&["--source-auth-max-failures", "0"], "source_auth_max_failures must be at least 1"),
+            (&["--max-sources", "0"], "max_sources must be at least 1"),
+            (&["--source-queue-depth", "0"], "source_queue_depth must be at least 1"),
+            (&["--max-destinations", "0"], "max_destinations must be at least 1"),
+            (&["--max-payload", "70000"], "max_payload must be at most 65535, got 70000"),
+            (&["--max-payload", "100", "--max-buffer", "107"], "max_buffer must be at least max_payload + 8 (108)"),
+            (&["--dest-queue-depth", "0"], "dest_queue_depth must be at least 1"),
+            (&["--dest-queue-depth", "4", "--history-messages", "5"], "history_messages must be at most dest_queue_depth (4)"),
+            (&["--history-secs", "60"], "history_secs requires history_messages to be set"),
+            (&["--source-tls-cert", "relay.crt"], "source_tls_cert and source_tls_key must be set together"),
+            (&["--dest-tls-key", "relay.key"], "dest_tls_cert and dest_tls_key must be set together"),
+            (&["--journal-segment-bytes", "0"], "journal_segment_bytes must be at least 1"),
+            (&["--journal-fsync-interval-ms", "0"], "journal_fsync_interval_ms must be at least 1"),
+            (&["--journal-segment-secs", "0"], "journal_segment_secs must be at least 1"),
+            (&["--multicast-group", "192.0.2.1:5000"], "multicast_group must be a multicast address"),
+            (&["--multicast-group", "239.1.2.3:5000", "--multicast-interface", "2"], "multicast_interface must be an IPv4 address"),
+            (&["--multicast-group", "[ff15::1]:5000", "--multicast-interface", "192.0.2.1"], "multicast_interface must be an interface index"),
+            (&["--multicast-interface", "192.0.2.1"], "multicast_interface requires multicast_group to be set"),
+            (&["--multicast-ttl", "256"], "multicast_ttl must be at most 255, got 256"),
+        ] {
+            let error = invalid(args);
+            assert!(error.starts_with(reason), "{:?} gave '{}', expected '{}'", args, error, reason);
+        }
+        // The limits themselves are accepted.
+        from_args(&["--dest-queue-depth", "4", "--history-messages", "4", "--max-payload", "100", "--max-buffer", "108", "--multicast-group", "239.1.2.3:5000", "--multicast-ttl", "255"]).unwrap();
+    }
+
+    #[test]
+    fn rejects_empty_listener_list() {
+        match from_file_and_args("dest_addr = []\n", &[]) {
+            Err(ConfigError::Invalid(reason)) => assert_eq!(reason, "source_addr and dest_addr must each have at least one address"),
+            other => panic!("empty dest_addr gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_log_level() {
+        assert!(invalid(&["--log-level", "tcp_server=loud"]).starts_with("invalid log level 'tcp_server=loud'"));
+        from_args(&["--log-level", "warn,tcp_server::destination=debug"]).unwrap();
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn async_runtime_requires_feature() {
+        assert_eq!(invalid(&["--runtime", "async"]), "the async runtime requires building with the async feature");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_runtime_rejects_unsupported_features() {
+        for (args, reason) in [
+            (&["--dest-overflow", "drop-newest"][..], "the async runtime does not support the drop-newest overflow policy"),
+            (&["--source-mode", "multi"], "the async runtime does not support the multi source mode"),
+            (&["--source-tls-cert", "relay.crt", "--source-tls-key", "relay.key"], "the async runtime does not support TLS"),
+            (&["--dest-tls-cert", "relay.crt", "--dest-tls-key", "relay.key"], "the async runtime does not support TLS"),
+            (&["--history-messages", "10"], "the async runtime does not support history replay"),
+            (&["--journal-dir", "journal"], "the async runtime does not support the journal"),
+            (&["--admin-socket", "admin.sock"], "the async runtime does not support the admin socket"),
+            (&["--multicast-group", "239.1.2.3:5000"], "the async runtime does not support multicast egress"),
+            (&["--ws-addr", "127.0.0.1:8080"], "the async runtime does not support WebSocket destinations"),
+            (&["--source-addr", "127.0.0.1:5000,127.0.0.1:5001"], "the async runtime only supports a single TCP address for source_addr and dest_addr"),
+            (&["--dest-addr", "unix:/tmp/relay.sock"], "the async runtime only supports a single TCP address for source_addr and dest_addr"),
+        ] {
+            let args = [&["--runtime", "async"], args].concat();
+            assert_eq!(invalid(&args), reason, "{:?}", args);
+        }
+        // The same settings are fine with the threaded runtime, which is the default.
+        from_args(&["--source-mode", "multi", "--history-messages", "10", "--ws-addr", "127.0.0.1:8080"]).unwrap();
+        from_args(&["--runtime", "async"]).unwrap();
+    }
+}
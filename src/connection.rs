@@ -0,0 +1,69 @@
+//! Connections to source and destination clients, which are either plain or TLS, over TCP or a Unix domain socket.
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::Arc;
+
+use rustls::ServerConfig;
+
+use crate::tls::{self, TlsStream};
+use crate::transport::Stream;
+
+/// A connected client.
+pub enum Connection {
+    Plain(Stream),
+    Tls(Box<TlsStream>),
+}
+
+impl Connection {
+    /// Sets up a newly accepted client, performing the TLS handshake if the listener has TLS enabled.
+    pub fn accept(stream: Stream, tls: Option<&Arc<ServerConfig>>) -> io::Result<Connection> {
+        match tls {
+            Some(config) => Ok(Connection::Tls(Box::new(tls::handshake(config, stream)?))),
+            None => Ok(Connection::Plain(stream)),
+        }
+    }
+
+    /// The underlying connection, e.g. to set timeouts or shut it down from another thread.
+    pub fn stream(&self) -> &Stream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Closes the connection, first telling a TLS client the session is ending. Errors are ignored, as the client may
+    /// already have gone.
+    pub fn shutdown(&mut self) {
+        if let Connection::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        let _ = self.stream().shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
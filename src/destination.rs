@@ -0,0 +1,581 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctmp::{CtmpMessage, Version};
+use rustls::ServerConfig;
+use tracing::{debug, info, info_span, warn};
+
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::history::History;
+use crate::metrics::{self, Metrics};
+use crate::subscription::{self, Channel, Subscription};
+use crate::transport::{Peer, Stream};
+use crate::websocket::{self, WsFormat};
+
+/// How often a WebSocket client is checked for pings and close frames.
+const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do when a destination's outbound queue is full and another message needs to be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Discard the new message, keeping everything already queued.
+    DropNewest,
+    /// Disconnect the destination, as it is not keeping up.
+    Disconnect,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy, String> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}', expected drop-oldest, drop-newest or disconnect", s)),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OverflowPolicy::DropOldest => "drop-oldest",
+            OverflowPolicy::DropNewest => "drop-newest",
+            OverflowPolicy::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// Protocol a destination client connected with, depending on the listener it connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Raw CTMP messages, after an optional hello line.
+    Ctmp,
+    /// CTMP messages wrapped in WebSocket messages, after a WebSocket upgrade. See the `websocket` module.
+    WebSocket,
+}
+
+/// How messages are written to a destination client, as negotiated in its handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Ctmp,
+    WebSocket(WsFormat),
+}
+
+/// Result of queueing a message for a destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Push {
+    Queued,
+    /// The queue was full, so a message was dropped to apply the overflow policy.
+    Dropped,
+    /// The queue was full and the destination has been disconnected.
+    Disconnected,
+    /// The destination had already been closed.
+    Closed,
+}
+
+/// Result of waiting for the next message to write to a destination.
+enum Popped {
+    Message(Arc<CtmpMessage>),
+    /// No message arrived before the timeout.
+    TimedOut,
+    /// The queue is closed and empty.
+    Closed,
+}
+
+/// State of a destination queue, protected by the queue's mutex.
+struct QueueState {
+    messages: VecDeque<Arc<CtmpMessage>>,
+    closed: bool,
+    /// Set when the destination is kicked through the admin interface, so its failed write is not reported as an error.
+    kicked: bool,
+}
+
+/// Bounded queue of messages waiting to be written to a single destination by its writer thread.
+struct DestinationQueue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl DestinationQueue {
+    fn new(capacity: usize, policy: OverflowPolicy) -> DestinationQueue {
+        DestinationQueue {
+            state: Mutex::new(QueueState { messages: VecDeque::with_capacity(capacity), closed: false, kicked: false }),
+            ready: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Queues a message for the writer thread, applying the overflow policy if the queue is full.
+    fn push(&self, message: &Arc<CtmpMessage>) -> Push {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Push::Closed;
+        }
+
+        let mut outcome = Push::Queued;
+        if state.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.messages.pop_front();
+                    outcome = Push::Dropped;
+                }
+                OverflowPolicy::DropNewest => return Push::Dropped,
+                OverflowPolicy::Disconnect => {
+                    // Anything still queued is thrown away, as the destination is being disconnected.
+                    state.messages.clear();
+                    state.closed = true;
+                    self.ready.notify_one();
+                    return Push::Disconnected;
+                }
+            }
+        }
+
+        state.messages.push_back(Arc::clone(message));
+        self.ready.notify_one();
+        outcome
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    /// Waits for the next message to write, for at most `timeout` if one is given. Returns `Popped::Closed` once the
+    /// queue is closed and there is nothing left to write.
+    fn pop(&self, timeout: Option<Duration>) -> Popped {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                return Popped::Message(message);
+            }
+            if state.closed {
+                return Popped::Closed;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Popped::TimedOut;
+                    }
+                    self.ready.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.ready.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Marks the queue as closed, so that no more messages are accepted and the writer thread exits.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Closes the queue and throws away anything still queued, so the writer thread exits straight away.
+    fn kick(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.messages.clear();
+        state.closed = true;
+        state.kicked = true;
+        self.ready.notify_one();
+    }
+
+    fn is_kicked(&self) -> bool {
+        self.state.lock().unwrap().kicked
+    }
+}
+
+/// A connected destination client, as seen by the broadcasting side.
+struct Destination {
+    id: u64,
+    peer: String,
+    connected: Instant,
+    /// The destination's connection, shut down to kick it while its writer thread may be blocked writing.
+    stream: Stream,
+    queue: Arc<DestinationQueue>,
+    /// What the destination subscribed to, or `None` while its handshake is still in progress.
+    subscription: Option<Subscription>,
+    /// Messages relayed during the handshake, queued once the destination's subscription is known.
+    pending: VecDeque<(Channel, Arc<CtmpMessage>)>,
+    /// History mark when the destination connected. Only messages recorded before it are replayed, as the rest are in
+    /// `pending`.
+    history_mark: u64,
+}
+
+/// A connected destination client, as listed by the admin interface.
+pub struct DestinationInfo {
+    pub id: u64,
+    pub peer: String,
+    /// How long the destination has been connected.
+    pub connected: Duration,
+    pub subscription: Subscription,
+    /// Number of messages waiting in its queue.
+    pub queued: usize,
+}
+
+/// Registry of the connected destination clients.
+/// Each destination has its own bounded queue and writer thread, so a slow destination only delays itself.
+pub struct Destinations {
+    list: Mutex<Vec<Destination>>,
+    next_id: AtomicU64,
+    metrics: Arc<Metrics>,
+    queue_depth: usize,
+    overflow_policy: OverflowPolicy,
+    max_destinations: Option<usize>,
+    versions: Vec<Version>,
+    hello_timeout: Duration,
+    tls: Option<Arc<ServerConfig>>,
+    /// Format of messages sent to WebSocket clients that do not ask for one.
+    ws_format: WsFormat,
+    /// Recently relayed messages replayed to new destinations, if enabled.
+    history: Option<History>,
+    /// Set when the relay is shutting down, so no more destinations are registered.
+    closing: AtomicBool,
+    /// Number of destination threads still running, and a condvar signalled when one ends.
+    threads: Mutex<usize>,
+    thread_ended: Condvar,
+}
+
+impl Destinations {
+    /// Creates the registry. Destination clients connect using TLS if `tls` is set.
+    pub fn new(config: &Config, metrics: Arc<Metrics>, tls: Option<Arc<ServerConfig>>) -> Destinations {
+        Destinations {
+            list: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            metrics,
+            queue_depth: config.dest_queue_depth,
+            overflow_policy: config.dest_overflow,
+            max_destinations: config.max_destinations,
+            versions: config.source_versions.clone(),
+            hello_timeout: Duration::from_millis(config.dest_hello_timeout_ms),
+            tls,
+            ws_format: config.ws_format,
+            history: (config.history_messages > 0).then(|| History::new(config.history_messages, config.history_secs.map(Duration::from_secs))),
+            closing: AtomicBool::new(false),
+            threads: Mutex::new(0),
+            thread_ended: Condvar::new(),
+        }
+    }
+
+    /// Starts a thread for a newly connected destination client, which registers the destination, performs the TLS
+    /// handshake (if enabled) and the hello handshake or WebSocket upgrade, and then writes its queued messages.
+    pub fn add(self: &Arc<Self>, stream: Stream, peer: &Peer, protocol: Protocol) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let peer = peer.to_string();
+        let destinations = Arc::clone(self);
+        *self.threads.lock().unwrap() += 1;
+        thread::spawn(move || {
+            // Everything logged by this thread is tagged with the destination's ID and address.
+            let _span = info_span!("destination", id, %peer).entered();
+            destinations.serve(id, peer, stream, protocol);
+            *destinations.threads.lock().unwrap() -= 1;
+            destinations.thread_ended.notify_all();
+        });
+    }
+
+    /// Function run by each destination's thread.
+    fn serve(&self, id: u64, peer: String, stream: Stream, protocol: Protocol) {
+        // The destination is registered before its handshake, so messages relayed while the relay waits for its hello
+        // are kept for it rather than lost.
+        let registered = stream.try_clone().map_err(|e| e.to_string()).and_then(|clone| self.register(id, peer, clone).map_err(str::to_string));
+        if let Err(reason) = registered {
+            warn!(reason, max_destinations = self.max_destinations, "destination rejected");
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        let mut connection = match Connection::accept(stream, self.tls.as_ref()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(error = %e, "destination TLS handshake failed");
+                self.remove(id);
+                return;
+            }
+        };
+        let handshake = match protocol {
+            Protocol::Ctmp => subscription::handshake(&mut connection, &self.versions, self.hello_timeout).map(|subscription| (subscription, Framing::Ctmp)),
+            Protocol::WebSocket => websocket::handshake(&mut connection, &self.versions, self.ws_format).map(|(subscription, format)| (subscription, Framing::WebSocket(format))),
+        };
+        let (subscription, framing) = match handshake {
+            Ok(handshake) => handshake,
+            Err(e) => {
+                warn!(error = %e, "destination handshake failed");
+                self.remove(id);
+                connection.shutdown();
+                return;
+            }
+        };
+
+        match self.activate(id, subscription.clone()) {
+            Ok((queue, replayed)) => {
+                let websocket = match framing {
+                    Framing::Ctmp => None,
+                    Framing::WebSocket(format) => Some(format.to_string()),
+                };
+                info!(versions = %subscription::join(&subscription.versions), channels = %subscription.channels.join(","), filter = %subscription.filter, replayed, websocket, "destination connected");
+                self.write_destination(connection, &queue, framing);
+            }
+            Err(reason) => {
+                warn!(reason, "destination rejected");
+                connection.shutdown();
+            }
+        }
+    }
+
+    /// Adds a newly connected destination to the registry, to keep the messages relayed during its handshake. Returns
+    /// the reason if the maximum number of destinations are already connected, or the relay is shutting down.
+    fn register(&self, id: u64, peer: String, stream: Stream) -> Result<(), &'static str> {
+        let mut list = self.list.lock().unwrap();
+        if self.closing.load(Ordering::Relaxed) {
+            return Err("relay shutting down");
+        }
+        if self.max_destinations.is_some_and(|max| list.len() >= max) {
+            return Err("maximum number of destinations connected");
+        }
+
+        let queue = Arc::new(DestinationQueue::new(self.queue_depth, self.overflow_policy));
+        let history_mark = self.history.as_ref().map_or(0, History::mark);
+        list.push(Destination { id, peer, connected: Instant::now(), stream, queue, subscription: None, pending: VecDeque::new(), history_mark });
+        Ok(())
+    }
+
+    /// Sets the subscription of a destination that has completed its handshake, returning its queue and the number of
+    /// messages replayed from the history. Returns the reason if the destination was kicked during its handshake, or the
+    /// relay is shutting down.
+    fn activate(&self, id: u64, subscription: Subscription) -> Result<(Arc<DestinationQueue>, usize), &'static str> {
+        let mut list = self.list.lock().unwrap();
+        if self.closing.load(Ordering::Relaxed) {
+            return Err("relay shutting down");
+        }
+        let Some(dest) = list.iter_mut().find(|dest| dest.id == id) else {
+            return Err("destination kicked");
+        };
+
+        // The history and the messages relayed during the handshake are queued while the registry is locked, so no
+        // message is either missed or delivered twice before live traffic. Messages relayed during the handshake are
+        // also recorded in the history, which is no larger than the queue, so both normally fit together. Should they
+        // not, the oldest replayed messages are left out, as those relayed during the handshake are live traffic.
+        let pending: Vec<Arc<CtmpMessage>> = dest.pending.drain(..).filter(|(channel, message)| subscription.accepts(channel, message)).map(|(_, message)| message).collect();
+        let mut replay = self.history.as_ref().map(|history| history.replay(&subscription, subscription.history, dest.history_mark)).unwrap_or_default();
+        let room = self.queue_depth.saturating_sub(pending.len());
+        if replay.len() > room {
+            let dropped = replay.len() - room;
+            debug!(dropped, "destination queue full, oldest history messages not replayed");
+            metrics::add(&self.metrics.queue_drops, dropped as u64);
+            replay.drain(..dropped);
+        }
+        metrics::add(&self.metrics.history_replayed, replay.len() as u64);
+
+        // The queue is still empty and everything fits, so the overflow policy never applies here.
+        for message in replay.iter().chain(&pending) {
+            let pushed = dest.queue.push(message);
+            debug_assert_eq!(pushed, Push::Queued);
+        }
+
+        dest.subscription = Some(subscription);
+        Ok((Arc::clone(&dest.queue), replay.len()))
+    }
+
+    /// Removes a destination whose handshake failed.
+    fn remove(&self, id: u64) {
+        self.list.lock().unwrap().retain(|dest| dest.id != id);
+    }
+
+    /// Queues a message relayed on the given channel for every destination client subscribed to it. Destinations that
+    /// have disconnected are removed.
+    pub fn broadcast(&self, channel: &Channel, message: CtmpMessage) {
+        let message = Arc::new(message);
+        let mut list = self.list.lock().unwrap();
+        if let Some(history) = &self.history {
+            history.record(channel, &message);
+        }
+        list.retain_mut(|dest| {
+            // Until its handshake is complete, a destination's messages are kept without knowing which it wants, up to
+            // the queue depth. A destination still in its handshake is not lagging, so it is never disconnected; the
+            // oldest message is dropped instead.
+            let Some(subscription) = &dest.subscription else {
+                if dest.pending.len() >= self.queue_depth {
+                    metrics::add(&self.metrics.queue_drops, 1);
+                    if self.overflow_policy == OverflowPolicy::DropNewest {
+                        return !dest.queue.is_closed();
+                    }
+                    dest.pending.pop_front();
+                }
+                dest.pending.push_back((Arc::clone(channel), Arc::clone(&message)));
+                return !dest.queue.is_closed();
+            };
+            if !subscription.accepts(channel, &message) {
+                return !dest.queue.is_closed();
+            }
+            match dest.queue.push(&message) {
+                Push::Queued => true,
+                Push::Dropped => {
+                    debug!(destination = dest.id, policy = %self.overflow_policy, "destination queue full, message dropped");
+                    metrics::add(&self.metrics.queue_drops, 1);
+                    true
+                }
+                Push::Disconnected => {
+                    warn!(destination = dest.id, "destination queue full, disconnecting lagging destination");
+                    metrics::add(&self.metrics.destinations_removed_lagging, 1);
+                    false
+                }
+                Push::Closed => false,
+            }
+        });
+    }
+
+    /// Closes every destination's queue on shutdown, so each writer thread sends what is left in its queue and then
+    /// disconnects. Destinations still completing their handshake are turned away.
+    pub fn close(&self) {
+        let list = self.list.lock().unwrap();
+        self.closing.store(true, Ordering::Relaxed);
+        for dest in list.iter() {
+            dest.queue.close();
+        }
+    }
+
+    /// Waits for every destination thread to end, or for the timeout to pass. Returns the number still running.
+    pub fn wait_closed(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut threads = self.threads.lock().unwrap();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if *threads == 0 || remaining.is_zero() {
+                return *threads;
+            }
+            threads = self.thread_ended.wait_timeout(threads, remaining).unwrap().0;
+        }
+    }
+
+    /// Number of connected destination clients that have completed their handshake and are still open, as reported by
+    /// the metrics. Destinations still sending their hello are left out, as they are not yet sent any messages.
+    pub fn len(&self) -> usize {
+        self.list.lock().unwrap().iter().filter(|dest| dest.subscription.is_some() && !dest.queue.is_closed()).count()
+    }
+
+    /// Details of every connected destination client that has completed its handshake, in the order they connected.
+    pub fn list(&self) -> Vec<DestinationInfo> {
+        self.list
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|dest| !dest.queue.is_closed())
+            .filter_map(|dest| {
+                Some(DestinationInfo {
+                    id: dest.id,
+                    peer: dest.peer.clone(),
+                    connected: dest.connected.elapsed(),
+                    subscription: dest.subscription.clone()?,
+                    queued: dest.queue.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Disconnects a destination client straight away, throwing away the messages queued for it. Returns false if no
+    /// destination with that ID is connected.
+    pub fn kick(&self, id: u64) -> bool {
+        let mut list = self.list.lock().unwrap();
+        let Some(index) = list.iter().position(|dest| dest.id == id) else {
+            return false;
+        };
+        let dest = list.remove(index);
+        dest.queue.kick();
+        // Shutting down the connection interrupts a write the writer thread is blocked on.
+        let _ = dest.stream.shutdown(Shutdown::Both);
+        info!(destination = id, peer = %dest.peer, "destination kicked");
+        true
+    }
+
+    /// Number of messages waiting in each destination's queue, by destination ID.
+    pub fn queue_depths(&self) -> Vec<(u64, usize)> {
+        self.list.lock().unwrap().iter().map(|dest| (dest.id, dest.queue.len())).collect()
+    }
+
+    /// Function run by each destination's writer thread. Writes queued messages to the destination until the queue is
+    /// closed or a write fails.
+    fn write_destination(&self, mut connection: Connection, queue: &DestinationQueue, framing: Framing) {
+        // WebSocket frames are built in a buffer reused for every message. The client is polled for pings and close
+        // frames whenever no message arrives for a while, and between messages at the same interval when busy.
+        let mut frame = Vec::new();
+        let mut client = matches!(framing, Framing::WebSocket(_)).then(websocket::ClientReader::new);
+        let timeout = client.as_ref().map(|_| CLIENT_POLL_INTERVAL);
+        let mut polled = Instant::now();
+        let mut failed = false;
+        let mut client_closed = false;
+        loop {
+            let message = match queue.pop(timeout) {
+                Popped::Message(message) => Some(message),
+                Popped::TimedOut => None,
+                Popped::Closed => break,
+            };
+            if let Some(reader) = client.as_mut().filter(|_| message.is_none() || polled.elapsed() >= CLIENT_POLL_INTERVAL) {
+                polled = Instant::now();
+                match reader.poll(&mut connection) {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        info!("WebSocket client closed the connection");
+                        client_closed = true;
+                        queue.close();
+                        break;
+                    }
+                    Err(e) => {
+                        failed = true;
+                        if !queue.is_kicked() {
+                            warn!(error = %e, "read from WebSocket client failed, removing destination");
+                            metrics::add(&self.metrics.destinations_removed_on_error, 1);
+                            queue.close();
+                        }
+                        break;
+                    }
+                }
+            }
+            let Some(message) = message else {
+                continue;
+            };
+            let bytes = match framing {
+                Framing::Ctmp => message.as_bytes(),
+                Framing::WebSocket(format) => {
+                    websocket::encode(&message, format, &mut frame);
+                    &frame
+                }
+            };
+            if let Err(e) = connection.write_all(bytes) { // If there is an error with the connection, the destination is closed and removed on the next broadcast.
+                failed = true;
+                if queue.is_kicked() {
+                    break;
+                }
+                warn!(error = %e, "write to destination failed, removing destination");
+                metrics::add(&self.metrics.destinations_removed_on_error, 1);
+                queue.close();
+                break;
+            }
+            metrics::add(&self.metrics.bytes_out, bytes.len() as u64);
+        }
+
+        // A WebSocket client that is still connected is told the relay is going away, e.g. on shutdown.
+        if matches!(framing, Framing::WebSocket(_)) && !failed && !client_closed && !queue.is_kicked() {
+            let _ = connection.write_all(&websocket::CLOSE_FRAME);
+        }
+
+        connection.shutdown();
+        info!("destination disconnected");
+    }
+}
@@ -0,0 +1,310 @@
+//! Filters letting destination clients receive only the messages whose header or payload match, set with `filter=` in
+//! the destination hello line.
+//!
+//! A filter is a comma separated list of terms, all of which must match:
+//!
+//! | Term                | Matches messages                                              |
+//! |---------------------|---------------------------------------------------------------|
+//! | `sensitive`         | with the sensitive option bit (0x40) set                      |
+//! | `!sensitive`        | without the sensitive option bit set                          |
+//! | `len>N`, `len>=N`   | with a payload longer than (or at least) N bytes              |
+//! | `len<N`, `len<=N`   | with a payload shorter than (or at most) N bytes              |
+//! | `len=N`, `len=A..B` | with a payload of exactly N bytes, or A to B bytes            |
+//! | `options:MASK`      | with every option bit in MASK set, e.g. `options:0x40`        |
+//! | `!options:MASK`     | with none of the option bits in MASK set                      |
+//! | `prefix:HEX`        | whose payload starts with the given bytes, e.g. `prefix:0102` |
+//!
+//! For example `CTMP filter=!sensitive,len>=100` subscribes to non-sensitive messages with at least 100 bytes of payload.
+
+use std::fmt;
+use std::str::FromStr;
+
+use ctmp::{CtmpMessage, OPTION_SENSITIVE};
+
+/// A single condition of a filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    /// Every option bit in `mask` is set if `set` is true, or none of them are if it is false.
+    Options { mask: u8, set: bool },
+    /// The payload length is between `min` and `max`, inclusive.
+    Length { min: u16, max: u16 },
+    /// The payload starts with these bytes.
+    Prefix(Vec<u8>),
+}
+
+impl Term {
+    fn matches(&self, message: &CtmpMessage) -> bool {
+        match self {
+            Term::Options { mask, set: true } => message.header().options & mask == *mask,
+            Term::Options { mask, set: false } => message.header().options & mask == 0,
+            Term::Length { min, max } => (*min..=*max).contains(&message.header().length),
+            Term::Prefix(prefix) => message.payload().starts_with(prefix),
+        }
+    }
+}
+
+impl FromStr for Term {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Term, String> {
+        let (negated, rest) = match s.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        if rest == "sensitive" {
+            return Ok(Term::Options { mask: OPTION_SENSITIVE, set: !negated });
+        }
+        if let Some(mask) = rest.strip_prefix("options:") {
+            let mask = parse_number(mask).and_then(|mask| u8::try_from(mask).ok()).filter(|mask| *mask != 0);
+            let mask = mask.ok_or_else(|| format!("invalid option mask in '{}', expected a non-zero byte such as 0x40", s))?;
+            return Ok(Term::Options { mask, set: !negated });
+        }
+        if negated {
+            return Err(format!("'{}' cannot be negated, only sensitive and options:MASK can", rest));
+        }
+
+        if let Some(hex) = rest.strip_prefix("prefix:") {
+            return parse_hex(hex).map(Term::Prefix).ok_or_else(|| format!("invalid prefix in '{}', expected hex bytes such as 0102", s));
+        }
+        if let Some(condition) = rest.strip_prefix("len") {
+            return parse_length(condition).map(|(min, max)| Term::Length { min, max }).ok_or_else(|| format!("invalid length condition '{}'", s));
+        }
+        Err(format!("unknown filter term '{}'", s))
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Options { mask: OPTION_SENSITIVE, set } => write!(f, "{}sensitive", if *set { "" } else { "!" }),
+            Term::Options { mask, set } => write!(f, "{}options:{:#04x}", if *set { "" } else { "!" }, mask),
+            Term::Length { min, max } if min == max => write!(f, "len={}", min),
+            Term::Length { min, max: u16::MAX } => write!(f, "len>={}", min),
+            Term::Length { min: 0, max } => write!(f, "len<={}", max),
+            Term::Length { min, max } => write!(f, "len={}..{}", min, max),
+            Term::Prefix(prefix) => {
+                f.write_str("prefix:")?;
+                prefix.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+        }
+    }
+}
+
+/// Conditions a destination client's messages must meet. An empty filter matches every message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+impl Filter {
+    /// Returns true if the message matches every term of the filter.
+    pub fn matches(&self, message: &CtmpMessage) -> bool {
+        self.terms.iter().all(|term| term.matches(message))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let terms = s.split(',').map(str::parse).collect::<Result<_, _>>()?;
+        Ok(Filter { terms })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, term) in self.terms.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", term)?;
+        }
+        Ok(())
+    }
+}
+
+/// Function to parse the part of a length condition after "len", returning the inclusive range of lengths it allows.
+fn parse_length(condition: &str) -> Option<(u16, u16)> {
+    if let Some(n) = condition.strip_prefix(">=") {
+        return Some((n.parse().ok()?, u16::MAX));
+    }
+    if let Some(n) = condition.strip_prefix("<=") {
+        return Some((0, n.parse().ok()?));
+    }
+    if let Some(n) = condition.strip_prefix('>') {
+        return Some((n.parse::<u16>().ok()?.checked_add(1)?, u16::MAX));
+    }
+    if let Some(n) = condition.strip_prefix('<') {
+        return Some((0, n.parse::<u16>().ok()?.checked_sub(1)?));
+    }
+
+    let range = condition.strip_prefix('=')?;
+    let (min, max) = match range.split_once("..") {
+        Some((min, max)) => (min.parse().ok()?, max.parse().ok()?),
+        None => {
+            let n = range.parse().ok()?;
+            (n, n)
+        }
+    };
+    (min <= max).then_some((min, max))
+}
+
+/// Function to parse a number given in decimal, or in hex with a "0x" prefix.
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Function to parse a non-empty string of hex digit pairs into bytes.
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ctmp::Encoder;
+
+    fn message(options: u8, payload: &[u8]) -> CtmpMessage {
+        Encoder::new().options(options).encode(payload).unwrap()
+    }
+
+    fn filter(s: &str) -> Filter {
+        s.parse().unwrap_or_else(|e| panic!("'{}' did not parse: {}", s, e))
+    }
+
+    /// Function to check which payload lengths a filter matches, around the given boundaries.
+    fn matching_lengths(s: &str, lengths: &[usize]) -> Vec<usize> {
+        let filter = filter(s);
+        lengths.iter().copied().filter(|len| filter.matches(&message(0, &vec![0; *len]))).collect()
+    }
+
+    #[test]
+    fn rejects_invalid_terms() {
+        for (s, error) in [
+            ("prefix:abc", "invalid prefix in 'prefix:abc'"),
+            ("prefix:", "invalid prefix in 'prefix:'"),
+            ("prefix:zz", "invalid prefix in 'prefix:zz'"),
+            ("len=5..2", "invalid length condition 'len=5..2'"),
+            ("len<0", "invalid length condition 'len<0'"),
+            ("len>65535", "invalid length condition 'len>65535'"),
+            ("len=70000", "invalid length condition 'len=70000'"),
+            ("len", "invalid length condition 'len'"),
+            ("options:0", "invalid option mask in 'options:0'"),
+            ("options:0x100", "invalid option mask in 'options:0x100'"),
+            ("!prefix:01", "'prefix:01' cannot be negated"),
+            ("!len>5", "'len>5' cannot be negated"),
+            ("colour=red", "unknown filter term 'colour=red'"),
+            ("", "unknown filter term ''"),
+            ("sensitive,,len>5", "unknown filter term ''"),
+            ("sensitive,", "unknown filter term ''"),
+        ] {
+            let result = s.parse::<Filter>();
+            assert!(matches!(&result, Err(e) if e.starts_with(error)), "'{}' gave {:?}", s, result);
+        }
+    }
+
+    #[test]
+    fn length_ranges_are_inclusive() {
+        let lengths = [0, 1, 4, 5, 6, 9, 10, 11];
+        assert_eq!(matching_lengths("len=5..10", &lengths), [5, 6, 9, 10]);
+        assert_eq!(matching_lengths("len=5..5", &lengths), [5]);
+        assert_eq!(matching_lengths("len=5", &lengths), [5]);
+        assert_eq!(matching_lengths("len=0..0", &lengths), [0]);
+        assert_eq!(matching_lengths("len>5", &lengths), [6, 9, 10, 11]);
+        assert_eq!(matching_lengths("len>=5", &lengths), [5, 6, 9, 10, 11]);
+        assert_eq!(matching_lengths("len<5", &lengths), [0, 1, 4]);
+        assert_eq!(matching_lengths("len<=5", &lengths), [0, 1, 4, 5]);
+        assert_eq!(matching_lengths("len>=0", &lengths), lengths);
+        assert_eq!(matching_lengths("len>=5,len<=9", &lengths), [5, 6, 9]);
+    }
+
+    #[test]
+    fn length_at_limits() {
+        let lengths = [0, 65534, 65535];
+        assert_eq!(matching_lengths("len>65534", &lengths), [65535]);
+        assert_eq!(matching_lengths("len<1", &lengths), [0]);
+        assert_eq!(matching_lengths("len=0..65535", &lengths), lengths);
+    }
+
+    #[test]
+    fn option_masks_need_every_bit() {
+        let set = filter("options:0x41");
+        let none = filter("!options:0x41");
+        for (options, matches_set, matches_none) in [
+            (0x00, false, true),
+            (0x01, false, false),
+            (0x40, false, false),
+            (0x41, true, false),
+            (0x43, true, false),
+            (0x02, false, true),
+        ] {
+            let message = message(options, b"payload");
+            assert_eq!(set.matches(&message), matches_set, "options:0x41 against {:#04x}", options);
+            assert_eq!(none.matches(&message), matches_none, "!options:0x41 against {:#04x}", options);
+        }
+        // Masks may also be given in decimal.
+        assert_eq!(filter("options:65"), set);
+    }
+
+    #[test]
+    fn sensitive_and_negation() {
+        let sensitive = message(OPTION_SENSITIVE, b"secret");
+        let plain = message(0, b"public");
+        assert!(filter("sensitive").matches(&sensitive));
+        assert!(!filter("sensitive").matches(&plain));
+        assert!(!filter("!sensitive").matches(&sensitive));
+        assert!(filter("!sensitive").matches(&plain));
+        assert_eq!(filter("sensitive"), filter("options:0x40"));
+    }
+
+    #[test]
+    fn prefix_matches_start_of_payload() {
+        let prefix = filter("prefix:01AB");
+        assert!(prefix.matches(&message(0, &[0x01, 0xAB])));
+        assert!(prefix.matches(&message(0, &[0x01, 0xAB, 0xFF])));
+        assert!(!prefix.matches(&message(0, &[0x01])));
+        assert!(!prefix.matches(&message(0, &[0x01, 0xAC])));
+        assert!(!prefix.matches(&message(0, &[0xFF, 0x01, 0xAB])));
+        assert!(!prefix.matches(&message(0, b"")));
+    }
+
+    #[test]
+    fn all_terms_must_match() {
+        let filter = filter("!sensitive,len>=3,prefix:61");
+        assert!(filter.matches(&message(0, b"abc")));
+        assert!(!filter.matches(&message(OPTION_SENSITIVE, b"abc")));
+        assert!(!filter.matches(&message(0, b"ab")));
+        assert!(!filter.matches(&message(0, b"bcd")));
+    }
+
+    #[test]
+    fn displays_in_canonical_form() {
+        for (s, shown) in [
+            ("sensitive", "sensitive"),
+            ("!options:0x40", "!sensitive"),
+            ("options:3", "options:0x03"),
+            ("len>4", "len>=5"),
+            ("len<5", "len<=4"),
+            ("len=2..2", "len=2"),
+            ("len=2..9", "len=2..9"),
+            ("prefix:0A0b", "prefix:0a0b"),
+            ("!sensitive,len>=100", "!sensitive,len>=100"),
+        ] {
+            assert_eq!(filter(s).to_string(), shown);
+            assert_eq!(filter(shown), filter(s));
+        }
+    }
+}
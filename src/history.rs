@@ -0,0 +1,128 @@
+//! In-memory history of the most recently relayed messages, replayed to each new destination client before live traffic
+//! so that a consumer that restarts does not lose context.
+//!
+//! The relay keeps the last `history_messages` messages, optionally only those received in the last `history_secs`
+//! seconds. A destination client chooses how much of it to replay with `history=` in its hello line:
+//!
+//! | Value     | Replays                                              |
+//! |-----------|------------------------------------------------------|
+//! | `all`     | every message kept (the default)                     |
+//! | `N`       | the last N messages the destination subscribes to    |
+//! | `Ns`      | the messages received in the last N seconds          |
+//! | `0`       | nothing, only live traffic                           |
+//!
+//! Only messages matching the destination's channels, versions and filter are replayed. Messages relayed while a
+//! destination's handshake is still in progress are delivered live instead, so they are not replayed.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ctmp::CtmpMessage;
+
+use crate::subscription::{Channel, Subscription};
+
+/// How much history a destination client asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRequest {
+    /// Every message kept.
+    All,
+    /// The last N messages matching the subscription.
+    Messages(usize),
+    /// The messages received in the last N seconds.
+    Seconds(u64),
+}
+
+impl FromStr for HistoryRequest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HistoryRequest, String> {
+        let invalid = || format!("invalid history '{}', expected all, a number of messages such as 100, or seconds such as 30s", s);
+        if s == "all" {
+            return Ok(HistoryRequest::All);
+        }
+        match s.strip_suffix('s') {
+            Some(seconds) => seconds.parse().map(HistoryRequest::Seconds).map_err(|_| invalid()),
+            None => s.parse().map(HistoryRequest::Messages).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for HistoryRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryRequest::All => f.write_str("all"),
+            HistoryRequest::Messages(count) => write!(f, "{}", count),
+            HistoryRequest::Seconds(seconds) => write!(f, "{}s", seconds),
+        }
+    }
+}
+
+/// A relayed message kept in the history.
+struct Entry {
+    channel: Channel,
+    message: Arc<CtmpMessage>,
+    received: Instant,
+    /// Number of messages recorded before this one.
+    sequence: u64,
+}
+
+/// Ring buffer of the most recently relayed messages, on every channel.
+pub struct History {
+    entries: Mutex<VecDeque<Entry>>,
+    capacity: usize,
+    max_age: Option<Duration>,
+    /// Number of messages recorded since the relay started.
+    recorded: AtomicU64,
+}
+
+impl History {
+    pub fn new(capacity: usize, max_age: Option<Duration>) -> History {
+        History { entries: Mutex::new(VecDeque::with_capacity(capacity)), capacity, max_age, recorded: AtomicU64::new(0) }
+    }
+
+    /// Adds a relayed message, discarding the oldest message if the history is full.
+    pub fn record(&self, channel: &Channel, message: &Arc<CtmpMessage>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        let sequence = self.recorded.fetch_add(1, Ordering::Relaxed);
+        entries.push_back(Entry { channel: Arc::clone(channel), message: Arc::clone(message), received: Instant::now(), sequence });
+    }
+
+    /// Returns a mark of how many messages have been recorded so far, to replay only the messages recorded before it.
+    pub fn mark(&self) -> u64 {
+        self.recorded.load(Ordering::Relaxed)
+    }
+
+    /// Returns the messages recorded before `mark` to replay to a destination client, oldest first: those it subscribes
+    /// to, limited to what it requested and to the history's maximum age.
+    pub fn replay(&self, subscription: &Subscription, request: HistoryRequest, mark: u64) -> Vec<Arc<CtmpMessage>> {
+        let mut max_age = self.max_age;
+        if let HistoryRequest::Seconds(seconds) = request {
+            let requested = Duration::from_secs(seconds);
+            max_age = Some(max_age.map_or(requested, |age| age.min(requested)));
+        }
+        let limit = match request {
+            HistoryRequest::Messages(count) => count,
+            _ => usize::MAX,
+        };
+
+        let entries = self.entries.lock().unwrap();
+        let mut replay: Vec<Arc<CtmpMessage>> = entries
+            .iter()
+            .rev()
+            .skip_while(|entry| entry.sequence >= mark)
+            .take_while(|entry| max_age.is_none_or(|age| entry.received.elapsed() <= age))
+            .filter(|entry| subscription.accepts(&entry.channel, &entry.message))
+            .take(limit)
+            .map(|entry| Arc::clone(&entry.message))
+            .collect();
+        replay.reverse();
+        replay
+    }
+}
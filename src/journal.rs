@@ -0,0 +1,525 @@
+//! Append-only journal of every message accepted from the sources, kept on disk as an auditable record of everything
+//! the relay forwarded.
+//!
+//! The journal is a directory of segment files, named after the sequence number of their first record (e.g.
+//! `00000000000000000001.journal`) so they sort in order. Each segment starts with the 8 byte marker `CTMPJRN1`,
+//! followed by records of, in big-endian order:
+//!
+//! | Field       | Size     | Contents                                                            |
+//! |-------------|----------|---------------------------------------------------------------------|
+//! | length      | 4 bytes  | Length of the rest of the record                                    |
+//! | sequence    | 8 bytes  | Sequence number, starting at 1 and carried on across restarts       |
+//! | received    | 8 bytes  | When the message was accepted, in microseconds since the Unix epoch |
+//! | channel_len | 1 byte   | Length of the channel name                                          |
+//! | channel     | variable | Channel the message was sent on                                     |
+//! | frame       | variable | The complete CTMP message as received                               |
+//!
+//! A new segment is started when the current one would grow past the segment size or has been open longer than the
+//! segment age, and every time the relay starts. Old segments are deleted once the journal is larger than the retention
+//! size, or once their newest record is older than the retention age.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ctmp::{CtmpMessage, HEADER_LEN};
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::metrics::{self, Metrics};
+use crate::subscription::MAX_CHANNEL_LEN;
+
+/// Marker at the start of every segment file, identifying the format.
+const SEGMENT_MAGIC: &[u8; 8] = b"CTMPJRN1";
+
+/// File extension of segment files. Other files in the journal directory are left alone.
+const SEGMENT_EXTENSION: &str = "journal";
+
+/// Length of the fixed fields of a record after its length field: sequence, received time and channel length.
+const RECORD_FIXED_LEN: usize = 8 + 8 + 1;
+
+/// Longest possible record after its length field, with the longest channel name and payload. A longer length can only
+/// come from a damaged record.
+const RECORD_MAX_LEN: usize = RECORD_FIXED_LEN + MAX_CHANNEL_LEN + HEADER_LEN + u16::MAX as usize;
+
+/// When journal writes are flushed to disk with fsync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record, so no acknowledged record is lost in a crash, at the cost of throughput.
+    Always,
+    /// Periodically from a background thread, losing at most the last interval's records in a crash.
+    Interval,
+    /// Only when a segment is closed, leaving it to the operating system otherwise.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => Ok(FsyncPolicy::Interval),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy '{}', expected always, interval or never", s)),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::Interval => "interval",
+            FsyncPolicy::Never => "never",
+        })
+    }
+}
+
+/// The segment file records are currently appended to.
+struct Segment {
+    file: File,
+    path: PathBuf,
+    size: u64,
+    opened: Instant,
+    /// Whether records have been written since the last fsync.
+    dirty: bool,
+}
+
+/// State of the journal shared by the threads appending to it.
+struct Writer {
+    /// `None` until the first record is written, and after a failed write, so the next record starts a new segment.
+    segment: Option<Segment>,
+    next_sequence: u64,
+}
+
+/// The on-disk journal. Appending is serialised by a lock, so records are numbered in the order they are accepted.
+pub struct Journal {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    segment_bytes: u64,
+    segment_age: Option<Duration>,
+    retention_bytes: Option<u64>,
+    retention_age: Option<Duration>,
+    writer: Mutex<Writer>,
+    metrics: Arc<Metrics>,
+}
+
+impl Journal {
+    /// Opens the journal in the configured directory, creating it if needed, and carries on the sequence numbers from
+    /// the records already in it. Returns `None` if the journal is not enabled.
+    pub fn open(config: &Config, metrics: Arc<Metrics>) -> io::Result<Option<Arc<Journal>>> {
+        let Some(dir) = &config.journal_dir else {
+            return Ok(None);
+        };
+        fs::create_dir_all(dir).map_err(|e| io::Error::new(e.kind(), format!("could not create journal directory {}: {}", dir.display(), e)))?;
+
+        // The last segment may end with a record cut short by a crash, which is skipped. New records always go into a
+        // new segment, so a damaged segment is never appended to.
+        let next_sequence = match segments(dir)?.last() {
+            Some(last) => last_record(&last.path)?.map_or(last.first_sequence, |record| record.sequence + 1),
+            None => 1,
+        };
+
+        let journal = Arc::new(Journal {
+            dir: dir.clone(),
+            fsync: config.journal_fsync,
+            segment_bytes: config.journal_segment_bytes,
+            segment_age: config.journal_segment_secs.map(Duration::from_secs),
+            retention_bytes: config.journal_retention_bytes,
+            retention_age: config.journal_retention_secs.map(Duration::from_secs),
+            writer: Mutex::new(Writer { segment: None, next_sequence }),
+            metrics,
+        });
+        journal.enforce_retention(None);
+        info!(dir = %dir.display(), next_sequence, fsync = %journal.fsync, "journal opened");
+
+        // Thread to periodically flush the records written since the last flush to disk.
+        if journal.fsync == FsyncPolicy::Interval {
+            let interval = Duration::from_millis(config.journal_fsync_interval_ms);
+            let journal = Arc::clone(&journal);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                journal.sync();
+            });
+        }
+        Ok(Some(journal))
+    }
+
+    /// Appends a message accepted on a channel. A failed write is logged and counted, but does not stop the message
+    /// from being relayed.
+    pub fn append(&self, channel: &str, message: &CtmpMessage) {
+        let received = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_micros() as u64);
+        let mut writer = self.writer.lock().unwrap();
+        match self.write_record(&mut writer, received, channel, message) {
+            Ok(()) => metrics::add(&self.metrics.journal_records, 1),
+            Err(e) => {
+                error!(error = %e, "failed to write message to journal");
+                metrics::add(&self.metrics.journal_errors, 1);
+                // The segment may now end with part of a record, so the next record starts a new one.
+                writer.segment = None;
+            }
+        }
+    }
+
+    /// Function to write a single record, starting a new segment first if needed.
+    fn write_record(&self, writer: &mut Writer, received: u64, channel: &str, message: &CtmpMessage) -> io::Result<()> {
+        let frame = message.as_bytes();
+        let length = RECORD_FIXED_LEN + channel.len() + frame.len();
+        let mut record = Vec::with_capacity(4 + length);
+        record.extend_from_slice(&(length as u32).to_be_bytes());
+        record.extend_from_slice(&writer.next_sequence.to_be_bytes());
+        record.extend_from_slice(&received.to_be_bytes());
+        // Channel names are at most 64 bytes long, checked when a source names its channel.
+        record.push(channel.len() as u8);
+        record.extend_from_slice(channel.as_bytes());
+        record.extend_from_slice(frame);
+
+        let full = writer.segment.as_ref().is_some_and(|segment| {
+            let too_large = segment.size > SEGMENT_MAGIC.len() as u64 && segment.size + record.len() as u64 > self.segment_bytes;
+            too_large || self.segment_age.is_some_and(|age| segment.opened.elapsed() >= age)
+        });
+        if full {
+            self.close_segment(writer);
+        }
+        if writer.segment.is_none() {
+            writer.segment = Some(self.open_segment(writer.next_sequence)?);
+            self.enforce_retention(writer.segment.as_ref().map(|segment| segment.path.as_path()));
+        }
+
+        let segment = writer.segment.as_mut().expect("segment opened above");
+        segment.file.write_all(&record)?;
+        segment.size += record.len() as u64;
+        segment.dirty = true;
+        // The record is complete in the segment even if flushing it fails, so its sequence number is used up.
+        writer.next_sequence += 1;
+        if self.fsync == FsyncPolicy::Always {
+            segment.file.sync_data()?;
+            segment.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Function to create a new segment whose first record will have the given sequence number.
+    fn open_segment(&self, first_sequence: u64) -> io::Result<Segment> {
+        let path = self.dir.join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION));
+        // A segment with this name can only exist if no record was written to it, so it is safe to overwrite.
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)?;
+        file.write_all(SEGMENT_MAGIC)?;
+        debug!(segment = %path.display(), "journal segment started");
+        Ok(Segment { file, path, size: SEGMENT_MAGIC.len() as u64, opened: Instant::now(), dirty: false })
+    }
+
+    /// Function to close the current segment, flushing it to disk first whatever the fsync policy.
+    fn close_segment(&self, writer: &mut Writer) {
+        if let Some(segment) = writer.segment.take()
+            && let Err(e) = segment.file.sync_data()
+        {
+            warn!(error = %e, segment = %segment.path.display(), "failed to flush journal segment");
+        }
+    }
+
+    /// Closes the current segment on shutdown, flushing it to disk.
+    pub fn close(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        self.close_segment(&mut writer);
+    }
+
+    /// Flushes the records written since the last flush to disk.
+    fn sync(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(segment) = writer.segment.as_mut().filter(|segment| segment.dirty) {
+            match segment.file.sync_data() {
+                Ok(()) => segment.dirty = false,
+                Err(e) => warn!(error = %e, segment = %segment.path.display(), "failed to flush journal segment"),
+            }
+        }
+    }
+
+    /// Function to delete the oldest segments while the journal is over the retention size, or while their newest
+    /// record is older than the retention age. The segment being written to is never deleted.
+    fn enforce_retention(&self, current: Option<&Path>) {
+        if self.retention_bytes.is_none() && self.retention_age.is_none() {
+            return;
+        }
+        let segments = match segments(&self.dir) {
+            Ok(segments) => segments,
+            Err(e) => {
+                warn!(error = %e, "failed to list journal segments");
+                return;
+            }
+        };
+
+        let mut total: u64 = segments.iter().map(|segment| segment.size).sum();
+        for segment in segments.iter().filter(|segment| Some(segment.path.as_path()) != current) {
+            let over_size = self.retention_bytes.is_some_and(|max| total > max);
+            let expired = !over_size && self.retention_age.is_some_and(|age| segment.newest().elapsed().is_ok_and(|elapsed| elapsed > age));
+            if !over_size && !expired {
+                break;
+            }
+            match fs::remove_file(&segment.path) {
+                Ok(()) => {
+                    total -= segment.size;
+                    info!(segment = %segment.path.display(), over_size, expired, "journal segment removed");
+                }
+                Err(e) => warn!(error = %e, segment = %segment.path.display(), "failed to remove journal segment"),
+            }
+        }
+    }
+}
+
+/// A segment file found in the journal directory.
+struct SegmentFile {
+    path: PathBuf,
+    first_sequence: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl SegmentFile {
+    /// When the newest record in the segment was received, or when the segment was last modified if it has no
+    /// complete records or cannot be read.
+    fn newest(&self) -> SystemTime {
+        match last_record(&self.path) {
+            Ok(Some(record)) => UNIX_EPOCH + Duration::from_micros(record.received),
+            _ => self.modified,
+        }
+    }
+}
+
+/// Function to list the segment files in the journal directory, oldest first.
+fn segments(dir: &Path) -> io::Result<Vec<SegmentFile>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != SEGMENT_EXTENSION) {
+            continue;
+        }
+        let Some(first_sequence) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        segments.push(SegmentFile { path, first_sequence, size: metadata.len(), modified: metadata.modified()? });
+    }
+    segments.sort_by_key(|segment| segment.first_sequence);
+    Ok(segments)
+}
+
+/// The fields of a record needed to open and clean up the journal.
+struct RecordInfo {
+    sequence: u64,
+    /// When the message was received, in microseconds since the Unix epoch.
+    received: u64,
+}
+
+/// Function to find the last complete record in a segment, or `None` if it has none.
+fn last_record(path: &Path) -> io::Result<Option<RecordInfo>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; SEGMENT_MAGIC.len()];
+    if reader.read_exact(&mut magic).is_err() || &magic != SEGMENT_MAGIC {
+        return Ok(None);
+    }
+
+    let mut last = None;
+    let mut record = Vec::new();
+    loop {
+        let mut length = [0u8; 4];
+        if reader.read_exact(&mut length).is_err() {
+            break;
+        }
+        // A length that no record can have means the record is damaged, so it ends the segment like a record cut
+        // short, rather than being trusted with an allocation.
+        let length = u32::from_be_bytes(length) as usize;
+        if !(RECORD_FIXED_LEN..=RECORD_MAX_LEN).contains(&length) {
+            break;
+        }
+        record.resize(length, 0);
+        // A record cut short ends the segment.
+        if reader.read_exact(&mut record).is_err() {
+            break;
+        }
+        let field = |offset: usize| u64::from_be_bytes(record[offset..offset + 8].try_into().expect("record is longer than 16 bytes"));
+        last = Some(RecordInfo { sequence: field(0), received: field(8) });
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use ctmp::Encoder;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const CHANNEL: &str = "default";
+
+    fn config(dir: &TempDir) -> Config {
+        Config { journal_dir: Some(dir.path().to_path_buf()), journal_fsync: FsyncPolicy::Never, ..Config::default() }
+    }
+
+    fn open(config: &Config) -> Arc<Journal> {
+        Journal::open(config, Arc::new(Metrics::default())).unwrap().unwrap()
+    }
+
+    fn message() -> CtmpMessage {
+        Encoder::new().encode(b"journalled").unwrap()
+    }
+
+    /// Length of a record holding `message()` on `CHANNEL`, including its length field.
+    fn record_len() -> u64 {
+        (4 + RECORD_FIXED_LEN + CHANNEL.len() + message().as_bytes().len()) as u64
+    }
+
+    /// First sequence number of every segment in the journal, oldest first.
+    fn first_sequences(dir: &TempDir) -> Vec<u64> {
+        segments(dir.path()).unwrap().iter().map(|segment| segment.first_sequence).collect()
+    }
+
+    fn segment_path(dir: &TempDir, first_sequence: u64) -> PathBuf {
+        dir.path().join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION))
+    }
+
+    #[test]
+    fn sequence_carries_over_restarts() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let journal = open(&config);
+        for _ in 0..3 {
+            journal.append(CHANNEL, &message());
+        }
+        journal.close();
+        drop(journal);
+
+        // Every start begins a new segment, numbered after the last record written before.
+        let journal = open(&config);
+        assert_eq!(journal.writer.lock().unwrap().next_sequence, 4);
+        journal.append(CHANNEL, &message());
+        journal.close();
+        assert_eq!(first_sequences(&dir), vec![1, 4]);
+        assert_eq!(last_record(&segment_path(&dir, 1)).unwrap().unwrap().sequence, 3);
+        assert_eq!(last_record(&segment_path(&dir, 4)).unwrap().unwrap().sequence, 4);
+    }
+
+    #[test]
+    fn rotates_segments_by_size() {
+        let dir = TempDir::new().unwrap();
+        let config = Config { journal_segment_bytes: SEGMENT_MAGIC.len() as u64 + 2 * record_len(), ..config(&dir) };
+        let journal = open(&config);
+        for _ in 0..5 {
+            journal.append(CHANNEL, &message());
+        }
+        journal.close();
+        assert_eq!(first_sequences(&dir), vec![1, 3, 5]);
+        assert_eq!(fs::metadata(segment_path(&dir, 1)).unwrap().len(), config.journal_segment_bytes);
+    }
+
+    #[test]
+    fn rotates_segments_by_age() {
+        let dir = TempDir::new().unwrap();
+        let journal = open(&Config { journal_segment_secs: Some(0), ..config(&dir) });
+        for _ in 0..3 {
+            journal.append(CHANNEL, &message());
+        }
+        journal.close();
+        assert_eq!(first_sequences(&dir), vec![1, 2, 3]);
+    }
+
+    /// Function to append a record with the given fsync policy and return whether it is still waiting to be flushed.
+    fn dirty_after_append(config: &Config) -> bool {
+        let journal = open(config);
+        journal.append(CHANNEL, &message());
+        journal.writer.lock().unwrap().segment.as_ref().unwrap().dirty
+    }
+
+    #[test]
+    fn fsync_policies() {
+        let dir = TempDir::new().unwrap();
+        assert!(!dirty_after_append(&Config { journal_fsync: FsyncPolicy::Always, ..config(&dir) }));
+        assert!(dirty_after_append(&Config { journal_fsync: FsyncPolicy::Never, ..config(&dir) }));
+
+        // With the interval policy the background thread flushes the segment.
+        let journal = open(&Config { journal_fsync: FsyncPolicy::Interval, journal_fsync_interval_ms: 10, ..config(&dir) });
+        journal.append(CHANNEL, &message());
+        thread::sleep(Duration::from_millis(200));
+        assert!(!journal.writer.lock().unwrap().segment.as_ref().unwrap().dirty);
+    }
+
+    #[test]
+    fn recovers_from_truncated_record() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let journal = open(&config);
+        for _ in 0..3 {
+            journal.append(CHANNEL, &message());
+        }
+        journal.close();
+        drop(journal);
+
+        // Cut the last record short, as a crash part way through writing it would.
+        let damaged = segment_path(&dir, 1);
+        let size = fs::metadata(&damaged).unwrap().len();
+        OpenOptions::new().write(true).open(&damaged).unwrap().set_len(size - 5).unwrap();
+
+        let journal = open(&config);
+        assert_eq!(journal.writer.lock().unwrap().next_sequence, 3);
+        journal.append(CHANNEL, &message());
+        journal.close();
+        // The damaged segment is left as it is and new records go into a new one.
+        assert_eq!(fs::metadata(&damaged).unwrap().len(), size - 5);
+        assert_eq!(first_sequences(&dir), vec![1, 3]);
+    }
+
+    #[test]
+    fn rejects_impossible_record_length() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let journal = open(&config);
+        journal.append(CHANNEL, &message());
+        journal.close();
+        drop(journal);
+
+        // A damaged length field must end the segment rather than be read as a 4 GiB record.
+        let mut file = OpenOptions::new().append(true).open(segment_path(&dir, 1)).unwrap();
+        file.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        file.write_all(&[0u8; 64]).unwrap();
+        assert_eq!(last_record(&segment_path(&dir, 1)).unwrap().unwrap().sequence, 1);
+        assert_eq!(open(&config).writer.lock().unwrap().next_sequence, 2);
+    }
+
+    #[test]
+    fn retention_by_size() {
+        let dir = TempDir::new().unwrap();
+        let segment_len = SEGMENT_MAGIC.len() as u64 + record_len();
+        let journal = open(&Config { journal_segment_secs: Some(0), journal_retention_bytes: Some(2 * segment_len), ..config(&dir) });
+        for _ in 0..5 {
+            journal.append(CHANNEL, &message());
+        }
+        journal.close();
+        assert_eq!(first_sequences(&dir), vec![4, 5]);
+    }
+
+    #[test]
+    fn retention_by_age_uses_newest_record() {
+        let dir = TempDir::new().unwrap();
+        let journal = open(&Config { journal_retention_secs: Some(3600), ..config(&dir) });
+
+        // A segment whose only record was received two hours ago, although the file was just written.
+        let two_hours_ago = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64 - 2 * 3600 * 1_000_000;
+        journal.write_record(&mut journal.writer.lock().unwrap(), two_hours_ago, CHANNEL, &message()).unwrap();
+        journal.close();
+        // A segment with a recent record.
+        journal.append(CHANNEL, &message());
+        journal.close();
+
+        // Starting a new segment removes the expired one only.
+        journal.append(CHANNEL, &message());
+        journal.close();
+        assert_eq!(first_sequences(&dir), vec![2, 3]);
+    }
+}
@@ -0,0 +1,75 @@
+//! Set up of the structured, levelled log output written to stderr.
+
+use std::fmt;
+use std::io::IsTerminal;
+use std::str::FromStr;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Format of the log output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, one per event.
+    Text,
+    /// One JSON object per event, for log pipelines to index.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+/// Function to parse a log level filter, such as "info" or "warn,tcp_server=debug".
+pub fn parse_filter(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|e| format!("invalid log level '{}': {}", level, e))
+}
+
+/// Handle to change the log level filter of the installed logger while the relay is running.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevel {
+    /// Replaces the log level filter, e.g. with "debug" or "warn,tcp_server::destination=debug".
+    pub fn set(&self, level: &str) -> Result<(), String> {
+        let filter = parse_filter(level)?;
+        self.handle.reload(filter).map_err(|e| format!("could not change log level: {}", e))
+    }
+}
+
+/// Function to install the global logger. Must be called once, before anything is logged. Returns a handle to change
+/// the log level later on.
+pub fn init(level: &str, format: LogFormat) -> Result<LogLevel, String> {
+    // The filter is wrapped in a reload layer so the admin interface can change it without restarting the relay.
+    let (filter, handle) = reload::Layer::new(parse_filter(level)?);
+    let ansi = std::io::stderr().is_terminal();
+    let registry = tracing_subscriber::registry().with(filter);
+    let layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr).with_ansi(ansi);
+
+    let result = match format {
+        LogFormat::Text => registry.with(layer).try_init(),
+        LogFormat::Json => registry.with(layer.json().flatten_event(true).with_current_span(true).with_span_list(false)).try_init(),
+    };
+    result.map_err(|e| format!("could not install logger: {}", e))?;
+    Ok(LogLevel { handle })
+}
@@ -1,131 +1,215 @@
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+#[cfg(feature = "async")]
+mod async_relay;
+mod acl;
+mod admin;
+mod arbiter;
+mod auth;
+mod config;
+mod connection;
+mod destination;
+mod filter;
+mod history;
+mod journal;
+mod logging;
+mod metrics;
+mod multicast;
+mod shutdown;
+mod source;
+mod subscription;
+mod tls;
+mod transport;
+mod websocket;
+
+use std::fs;
+use std::io;
+use std::process::ExitCode;
 use std::thread;
-use std::sync::{Arc, Mutex};
-
-fn main() -> std::io::Result<()> {
-    // Vector containing the TcpStreams of the destination source clients.
-    // Arc<> and Mutex<> used to ensure thread safety when accessing these destination client streams.
-    let destinations: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
-
-    // Clone of destinations to be owned by thread accepting destination clients.
-    let dest_list = Arc::clone(&destinations);
-
-    // TcpListener for the single source client.
-    let source_listener = TcpListener::bind("0.0.0.0:33333")?;
-
-    // TcpListener for the destination clients.
-    let dest_listener = TcpListener::bind("0.0.0.0:44444")?;
-
-    // Thread to run continuously in the background, accepting new destination clients.
-    thread::spawn(move || {
-        for stream in dest_listener.incoming() {
-            if let Ok(stream) = stream {
-                dest_list.lock().unwrap().push(stream);
-            }
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use acl::AccessList;
+use admin::Admin;
+use auth::Authenticator;
+use config::{Config, ConfigError, Runtime, SourceMode};
+use destination::{Destinations, Protocol};
+use journal::Journal;
+use multicast::Multicast;
+use logging::LogLevel;
+use metrics::Metrics;
+use shutdown::GracefulShutdown;
+use source::{ConnectedSources, SourceContext};
+use transport::{AcceptBackoff, ListenAddr, Listener};
+
+fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            print!("{}", ConfigError::Help);
+            return ExitCode::SUCCESS;
         }
-    });
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
 
-    // Loop to run continuously. This loop is necessary to allow for a new source client to connect if the current client disconnects.
-    loop {
-        let (source_stream, _) = source_listener.accept()?;
-        handle_source(source_stream, &destinations)?;
+    let log_level = match logging::init(&config.log_level, config.log_format) {
+        Ok(log_level) => log_level,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match config.runtime {
+        Runtime::Threaded => run(&config, log_level),
+        #[cfg(feature = "async")]
+        Runtime::Async => async_relay::run(&config),
+        #[cfg(not(feature = "async"))]
+        Runtime::Async => unreachable!("rejected when validating the config"),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!(error = %e, "relay stopped");
+            ExitCode::FAILURE
+        }
     }
 }
 
-/// Function to handle the messages sent by the current source client. Function is exited when the source disconnects.
-fn handle_source(mut source_stream: TcpStream, destinations: &Arc<Mutex<Vec<TcpStream>>>) -> std::io::Result<()> {
-    let mut buffer: Vec<u8> = Vec::new();
-    let mut read_buffer = [0u8; 1024];
+/// Function to run the threaded relay with the given settings. Returns once the relay has shut down on a signal, or
+/// with an error if it cannot start, e.g. because a listener cannot be bound. `log_level` is used by the admin
+/// interface to change the log level.
+fn run(config: &Config, log_level: LogLevel) -> io::Result<()> {
+    let metrics = Arc::new(Metrics::default());
 
-    loop {
-        let bytes_read = source_stream.read(&mut read_buffer)?;
-        if bytes_read == 0 { // If the source disconnects, exit the loop.
-            break;
-        }
-        buffer.extend_from_slice(&read_buffer[..bytes_read]);
-
-        // Loop to process all of the complete messages in the buffer.
-        loop {
-            // Look for magic byte.
-            if let Some(pos) = buffer.iter().position(|&b| b == 0xCC) {
-                if pos > 0 {
-                    // Discard anything before the magic byte.
-                    buffer.drain(..pos);
-                }
+    // Shutdown on SIGINT and SIGTERM, draining the relay for up to the shutdown timeout.
+    let shutdown = GracefulShutdown::install(Duration::from_millis(config.shutdown_timeout_ms))?;
 
-                if buffer.len() < 8 { // Message is not long enough to have the full header yet.
-                    break;
-                }
+    // TLS settings of each listener, if enabled.
+    let source_tls = tls::load(config.source_tls_cert.as_ref(), config.source_tls_key.as_ref())?;
+    let dest_tls = tls::load(config.dest_tls_cert.as_ref(), config.dest_tls_key.as_ref())?;
 
-                let length_bytes = &buffer[2..4];
-                let length = u16::from_be_bytes([length_bytes[0], length_bytes[1]]) as usize;
+    // Authentication of source clients, if enabled.
+    let auth = Authenticator::new(config)?;
 
-                let options = buffer[1];
-                let sensitive_bit = (options >> 6) & 1;
+    // Journal of every accepted message, if enabled.
+    let journal = Journal::open(config, Arc::clone(&metrics))?;
 
-                let checksum_bytes = &buffer[4..6];
-                let checksum = u16::from_be_bytes([checksum_bytes[0], checksum_bytes[1]]);
+    // Publisher of every relayed message to a multicast group, if enabled.
+    let multicast = Multicast::open(config, Arc::clone(&metrics))?;
 
-                if buffer.len() < 8 + length { // Full message has not been received yet.
-                    break;
-                }
+    // Registry of the destination clients. Each destination client has its own queue and writer thread.
+    // Arc<> used to share the registry with the thread accepting destination clients.
+    let destinations = Arc::new(Destinations::new(config, Arc::clone(&metrics), dest_tls.clone()));
 
-                let message: Vec<u8> = buffer.drain(..8 + length).collect();
+    // HTTP server exposing the metrics, if enabled.
+    if let Some(metrics_addr) = config.metrics_addr {
+        let metrics = Arc::clone(&metrics);
+        let destinations = Arc::clone(&destinations);
+        metrics::serve(metrics_addr, move || metrics.render(destinations.len(), &destinations.queue_depths()))?;
+        info!(addr = %metrics_addr, "serving metrics");
+    }
 
-                if sensitive_bit == 1 && !verify_checksum(&message, checksum) { // Checksum is checked and is not correct.
-                    eprintln!("Checksum invalid for message: {:?}, message dropped.", message);
-                    break;
-                }
+    // Source sessions being relayed, listed by the admin interface.
+    let sources = Arc::new(ConnectedSources::default());
+
+    // Unix domain socket serving admin commands, if enabled.
+    if let Some(admin_socket) = &config.admin_socket {
+        let admin = Admin { destinations: Arc::clone(&destinations), sources: Arc::clone(&sources), metrics: Arc::clone(&metrics), log_level };
+        admin::serve(admin_socket, admin)?;
+        info!(path = %admin_socket.display(), "serving admin commands");
+    }
+
+    // Listeners for the source client(s) and the destination clients, each on one or more TCP addresses or Unix domain
+    // sockets.
+    let bind = |addrs: &[ListenAddr]| addrs.iter().map(|addr| Listener::bind(addr, config.unix_socket_mode)).collect::<io::Result<Vec<_>>>();
+    let source_listeners = bind(&config.source_addr)?;
+    let dest_listeners = bind(&config.dest_addr)?;
+    let ws_listeners = bind(&config.ws_addr)?;
+
+    info!(
+        source_addr = %transport::join(&config.source_addr),
+        dest_addr = %transport::join(&config.dest_addr),
+        ws_addr = %transport::join(&config.ws_addr),
+        source_mode = %config.source_mode,
+        source_tls = source_tls.is_some(),
+        dest_tls = dest_tls.is_some(),
+        source_auth = %config.source_auth,
+        "relay listening"
+    );
+
+    // The accept loops are woken by connecting to the listeners once shutdown is requested.
+    for listener in source_listeners.iter().chain(&dest_listeners).chain(&ws_listeners) {
+        shutdown.add_listener(listener.local_addr()?);
+    }
 
-                // Broadcast message to destination clients.
-                // Vector to contain the indexes of the destination clients to be removed from destinations vector.
-                let mut to_remove = Vec::new();
-                let mut list = destinations.lock().unwrap();
-                for (i, dest) in list.iter_mut().enumerate() {
-                    if let Err(_) = dest.write_all(&message) { // If there is an error with the connection with a destination client, add it to the list of clients to be removed.
-                        to_remove.push(i);
+    // Threads to run in the background until shutdown, one per destination and WebSocket listener, accepting new
+    // destination clients from permitted addresses.
+    let dest_acl = AccessList::new(&config.dest_allow, &config.dest_deny);
+    let listeners = dest_listeners.into_iter().map(|listener| (listener, Protocol::Ctmp));
+    for (dest_listener, protocol) in listeners.chain(ws_listeners.into_iter().map(|listener| (listener, Protocol::WebSocket))) {
+        let dest_list = Arc::clone(&destinations);
+        let dest_acl = dest_acl.clone();
+        let dest_metrics = Arc::clone(&metrics);
+        let dest_shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            let mut backoff = AcceptBackoff::new();
+            loop {
+                let (stream, peer) = match dest_listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        thread::sleep(backoff.failed(&e, "destination"));
+                        continue;
                     }
+                };
+                backoff.succeeded();
+                if dest_shutdown.is_requested() {
+                    break;
                 }
-                // Clients are removed in reverse order in order to not offset the indexes of the other clients to be removed.
-                for i in to_remove.into_iter().rev() {
-                    list.remove(i);
+                if dest_acl.admit(&peer, "destination", &dest_metrics) {
+                    dest_list.add(stream, &peer, protocol);
                 }
-            } else {
-                // If there is no magic byte found, discard everything in the buffer.
-                buffer.clear();
-                break;
             }
-        }
+        });
     }
 
-    Ok(())
-}
-
-/// Function to verify the stated checksum against the given message and return an appropriate true/false value.
-fn verify_checksum(message: &[u8], checksum: u16) -> bool {
-    let mut sum: u32 = 0;
-
-    // Iterate over each 2-byte word in the message.
-    let mut message_index = 0;
-    while message_index < message.len() {
-        let word = if message_index == 4 { // The 2 bytes contained in the checksum field.
-            0xCCCCu16
-        } else {
-            let high_byte = message[message_index] as u16;
-            let low_byte = if message_index + 1 < message.len() { message[message_index + 1] as u16 } else { 0 };
-            (high_byte << 8) | low_byte
-        };
-
-        sum += word as u32; // Store sum as 32 bit to avoid any overflow.
-
-        while sum > 0xFFFF {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-
-        message_index += 2;
+    // Accept the source client(s) on this thread. Both modes return once shutdown is requested and the source sessions
+    // have ended, with every message they sent queued for the destinations.
+    let acl = AccessList::new(&config.source_allow, &config.source_deny);
+    let context = Arc::new(SourceContext {
+        config: config.clone(),
+        destinations: Arc::clone(&destinations),
+        metrics,
+        tls: source_tls,
+        auth,
+        acl,
+        journal: journal.clone(),
+        multicast,
+        shutdown: Arc::clone(&shutdown),
+        sources,
+    });
+    match config.source_mode {
+        SourceMode::Single => source::serve_single(source_listeners, context),
+        SourceMode::Multi => source::serve_multi(source_listeners, context),
     }
 
-    let computed = !(sum as u16); // Finds the one's complement of the calculated sum.
-    computed == checksum
-}
\ No newline at end of file
+    // Send each destination what is left in its queue before disconnecting it. Destinations that are still being
+    // written to when the timeout passes are cut off when the process exits.
+    destinations.close();
+    let writing = destinations.wait_closed(shutdown.remaining());
+    if writing > 0 {
+        warn!(writing, "shutdown timeout passed before every destination was sent its queued messages");
+    }
+    if let Some(journal) = &journal {
+        journal.close();
+    }
+    if let Some(admin_socket) = &config.admin_socket {
+        let _ = fs::remove_file(admin_socket);
+    }
+    info!("relay shut down");
+    Ok(())
+}
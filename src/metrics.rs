@@ -0,0 +1,153 @@
+//! Counters describing the relay's throughput and drops, served in the Prometheus text format.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use ctmp::FrameError;
+
+use crate::transport::AcceptBackoff;
+
+/// Counters and gauges shared by every part of the relay. Gauges that can be read from the relay's state when scraped
+/// (such as the number of connected destinations) are passed to `render` instead of being stored here.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub sources_connected: AtomicU64,
+    pub connections_denied: AtomicU64,
+    pub sources_rejected: AtomicU64,
+    pub sources_preempted: AtomicU64,
+    pub source_auth_failures: AtomicU64,
+    pub source_auth_blocked: AtomicU64,
+    pub frames_received: AtomicU64,
+    pub frames_relayed: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub garbage_bytes: AtomicU64,
+    pub checksum_failures: AtomicU64,
+    pub oversized_frames: AtomicU64,
+    pub invalid_headers: AtomicU64,
+    pub version_rejections: AtomicU64,
+    pub destinations_removed_on_error: AtomicU64,
+    pub destinations_removed_lagging: AtomicU64,
+    pub queue_drops: AtomicU64,
+    pub history_replayed: AtomicU64,
+    pub journal_records: AtomicU64,
+    pub journal_errors: AtomicU64,
+    pub multicast_datagrams: AtomicU64,
+    pub multicast_errors: AtomicU64,
+}
+
+/// Increments a counter by `n`.
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Decrements a gauge by one.
+pub fn sub(gauge: &AtomicU64) {
+    gauge.fetch_sub(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// Counts a frame rejected by the decoder under the counter for its reason.
+    pub fn record_frame_error(&self, error: &FrameError) {
+        let counter = match error {
+            FrameError::ChecksumMismatch { .. } => &self.checksum_failures,
+            FrameError::PayloadTooLarge { .. } => &self.oversized_frames,
+            _ => &self.invalid_headers,
+        };
+        add(counter, 1);
+    }
+
+    /// Renders the counters in the Prometheus text format, along with the gauges read from the relay's state.
+    pub fn render(&self, destinations: usize, queue_depths: &[(u64, usize)]) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("ctmp_connections_denied_total", "Source and destination clients refused by an IP access list.", &self.connections_denied),
+            ("ctmp_sources_rejected_total", "Source clients turned away because another source was connected or too many were.", &self.sources_rejected),
+            ("ctmp_sources_preempted_total", "Source clients disconnected to serve a newly connected source.", &self.sources_preempted),
+            ("ctmp_source_auth_failures_total", "Source clients that failed to authenticate.", &self.source_auth_failures),
+            ("ctmp_source_auth_blocked_total", "Source clients turned away for too many failed authentication attempts.", &self.source_auth_blocked),
+            ("ctmp_frames_received_total", "Frames accepted from the source, after validation and the version check.", &self.frames_received),
+            ("ctmp_frames_relayed_total", "Frames queued for delivery to destinations.", &self.frames_relayed),
+            ("ctmp_bytes_in_total", "Bytes read from the source.", &self.bytes_in),
+            ("ctmp_bytes_out_total", "Bytes written to destinations.", &self.bytes_out),
+            ("ctmp_garbage_bytes_total", "Bytes discarded from the source before a magic byte.", &self.garbage_bytes),
+            ("ctmp_checksum_failures_total", "Sensitive frames dropped for an invalid checksum.", &self.checksum_failures),
+            ("ctmp_oversized_frames_total", "Frames dropped for a payload above the maximum length.", &self.oversized_frames),
+            ("ctmp_invalid_headers_total", "Frames dropped for failing strict header validation.", &self.invalid_headers),
+            ("ctmp_version_rejections_total", "Frames dropped for a CTMP version not accepted from sources.", &self.version_rejections),
+            ("ctmp_destinations_removed_on_error_total", "Destinations removed after a failed write.", &self.destinations_removed_on_error),
+            ("ctmp_destinations_removed_lagging_total", "Destinations disconnected for overflowing their queue.", &self.destinations_removed_lagging),
+            ("ctmp_queue_drops_total", "Frames dropped from or not added to a full destination queue.", &self.queue_drops),
+            ("ctmp_history_replayed_total", "Frames replayed from the history to newly connected destinations.", &self.history_replayed),
+            ("ctmp_journal_records_total", "Frames appended to the journal.", &self.journal_records),
+            ("ctmp_journal_errors_total", "Frames that could not be appended to the journal.", &self.journal_errors),
+            ("ctmp_multicast_datagrams_total", "Frames published to the multicast group.", &self.multicast_datagrams),
+            ("ctmp_multicast_errors_total", "Frames that could not be published to the multicast group.", &self.multicast_errors),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(out, "# HELP ctmp_sources_connected Connected source clients.\n# TYPE ctmp_sources_connected gauge");
+        let _ = writeln!(out, "ctmp_sources_connected {}", self.sources_connected.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP ctmp_destinations_connected Connected destination clients that have completed their handshake.\n# TYPE ctmp_destinations_connected gauge");
+        let _ = writeln!(out, "ctmp_destinations_connected {}", destinations);
+        let _ = writeln!(out, "# HELP ctmp_destination_queue_depth Frames waiting in each destination's queue.\n# TYPE ctmp_destination_queue_depth gauge");
+        for (id, depth) in queue_depths {
+            let _ = writeln!(out, "ctmp_destination_queue_depth{{destination=\"{}\"}} {}", id, depth);
+        }
+        out
+    }
+}
+
+/// Function to start the HTTP server exposing the metrics on `GET /metrics`. `render` is called for every scrape.
+pub fn serve(addr: SocketAddr, render: impl Fn() -> String + Send + 'static) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+
+    // Scrapes are infrequent and cheap, so they are handled one at a time on a single thread.
+    thread::spawn(move || {
+        let mut backoff = AcceptBackoff::new();
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    thread::sleep(backoff.failed(&e, "metrics"));
+                    continue;
+                }
+            };
+            backoff.succeeded();
+            if let Err(e) = respond(stream, &render) {
+                tracing::warn!(error = %e, "failed to serve metrics request");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Function to answer a single HTTP request.
+fn respond(mut stream: TcpStream, render: &impl Fn() -> String) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Only the request line matters, e.g. "GET /metrics HTTP/1.1". The headers are read and ignored.
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string()),
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body)?;
+    stream.flush()
+}
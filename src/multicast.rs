@@ -0,0 +1,179 @@
+//! UDP multicast egress, publishing every relayed message to a multicast group so that any number of receivers on the
+//! local network get it for the cost of a single send.
+//!
+//! Each message is sent as one datagram of, in big-endian order:
+//!
+//! | Field       | Size     | Contents                                                             |
+//! |-------------|----------|----------------------------------------------------------------------|
+//! | sequence    | 8 bytes  | Sequence number, starting at 1 every time the relay starts           |
+//! | channel_len | 1 byte   | Length of the channel name                                           |
+//! | channel     | variable | Channel the message was sent on                                      |
+//! | frame       | variable | The complete CTMP message as received                                |
+//!
+//! Sequence numbers go up by one for every message, so a receiver that sees a gap knows it missed messages. A message
+//! that cannot be sent, e.g. because it is too long for a datagram, still uses up its sequence number.
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use ctmp::CtmpMessage;
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::metrics::{self, Metrics};
+
+/// Length of the fixed fields of a datagram: sequence and channel length.
+const DATAGRAM_FIXED_LEN: usize = 8 + 1;
+
+/// Network interface multicast datagrams are sent from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MulticastInterface {
+    /// An interface's IPv4 address, for an IPv4 group.
+    Addr(Ipv4Addr),
+    /// An interface's index, for an IPv6 group.
+    Index(u32),
+}
+
+impl FromStr for MulticastInterface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MulticastInterface, String> {
+        if let Ok(addr) = s.parse() {
+            return Ok(MulticastInterface::Addr(addr));
+        }
+        s.parse().map(MulticastInterface::Index).map_err(|_| format!("invalid interface '{}', expected an IPv4 address or an interface index", s))
+    }
+}
+
+impl fmt::Display for MulticastInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MulticastInterface::Addr(addr) => write!(f, "{}", addr),
+            MulticastInterface::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+/// State of the publisher, protected by its mutex so datagrams are numbered in the order they are sent.
+struct Sender {
+    next_sequence: u64,
+    /// Reused for every datagram, to avoid an allocation per message.
+    datagram: Vec<u8>,
+}
+
+/// Publisher of relayed messages to a multicast group.
+pub struct Multicast {
+    socket: UdpSocket,
+    group: SocketAddr,
+    sender: Mutex<Sender>,
+    metrics: Arc<Metrics>,
+}
+
+impl Multicast {
+    /// Sets up the socket publishing to the configured multicast group. Returns `None` if multicast egress is disabled.
+    pub fn open(config: &Config, metrics: Arc<Metrics>) -> io::Result<Option<Arc<Multicast>>> {
+        let Some(group) = config.multicast_group else {
+            return Ok(None);
+        };
+
+        let socket = Multicast::connect(group, config).map_err(|e| io::Error::new(e.kind(), format!("could not set up multicast group {}: {}", group, e)))?;
+        info!(%group, ttl = config.multicast_ttl, interface = config.multicast_interface.map(|interface| interface.to_string()), "publishing to multicast group");
+        Ok(Some(Arc::new(Multicast { socket, group, sender: Mutex::new(Sender { next_sequence: 1, datagram: Vec::new() }), metrics })))
+    }
+
+    /// Creates a UDP socket sending to the multicast group with the configured TTL and interface.
+    fn connect(group: SocketAddr, config: &Config) -> io::Result<UdpSocket> {
+        // The config is validated to have an interface of the same family as the group.
+        let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+        match group {
+            SocketAddr::V4(_) => {
+                socket.set_multicast_ttl_v4(config.multicast_ttl)?;
+                if let Some(MulticastInterface::Addr(addr)) = config.multicast_interface {
+                    socket.set_multicast_if_v4(&addr)?;
+                }
+            }
+            SocketAddr::V6(_) => {
+                socket.set_multicast_hops_v6(config.multicast_ttl)?;
+                if let Some(MulticastInterface::Index(index)) = config.multicast_interface {
+                    socket.set_multicast_if_v6(index)?;
+                }
+            }
+        }
+        let socket = UdpSocket::from(socket);
+        socket.connect(group)?;
+        Ok(socket)
+    }
+
+    /// Publishes a message relayed on a channel. A failed send is logged and counted, but does not stop the message
+    /// from being relayed to the destination clients.
+    pub fn publish(&self, channel: &str, message: &CtmpMessage) {
+        let mut sender = self.sender.lock().unwrap();
+        let sequence = sender.next_sequence;
+        sender.next_sequence += 1;
+
+        // Channel names are at most 64 bytes long, so the length fits in a byte.
+        let frame = message.as_bytes();
+        let datagram = &mut sender.datagram;
+        datagram.clear();
+        datagram.reserve(DATAGRAM_FIXED_LEN + channel.len() + frame.len());
+        datagram.extend_from_slice(&sequence.to_be_bytes());
+        datagram.push(channel.len() as u8);
+        datagram.extend_from_slice(channel.as_bytes());
+        datagram.extend_from_slice(frame);
+
+        match self.socket.send(datagram) {
+            Ok(_) => metrics::add(&self.metrics.multicast_datagrams, 1),
+            Err(e) => {
+                warn!(error = %e, group = %self.group, sequence, length = datagram.len(), "failed to send multicast datagram");
+                metrics::add(&self.metrics.multicast_errors, 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use ctmp::Encoder;
+
+    use super::*;
+
+    #[test]
+    fn publishes_numbered_datagrams() {
+        let group = Ipv4Addr::new(239, 255, 67, 77);
+        let receiver = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        receiver.join_multicast_v4(&group, &Ipv4Addr::LOCALHOST).unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let port = receiver.local_addr().unwrap().port();
+
+        let config = Config {
+            multicast_group: Some(SocketAddr::from((group, port))),
+            multicast_interface: Some(MulticastInterface::Addr(Ipv4Addr::LOCALHOST)),
+            ..Config::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+        let multicast = Multicast::open(&config, Arc::clone(&metrics)).unwrap().unwrap();
+
+        let messages = [Encoder::new().encode(b"first").unwrap(), Encoder::new().encode(b"second").unwrap()];
+        multicast.publish("prices", &messages[0]);
+        multicast.publish("prices", &messages[1]);
+
+        let mut datagram = [0u8; 512];
+        for (sequence, message) in (1u64..).zip(&messages) {
+            let len = receiver.recv(&mut datagram).unwrap();
+            let mut expected = sequence.to_be_bytes().to_vec();
+            expected.push(6);
+            expected.extend_from_slice(b"prices");
+            expected.extend_from_slice(message.as_bytes());
+            assert_eq!(&datagram[..len], &expected[..]);
+        }
+        assert_eq!(metrics.multicast_datagrams.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,158 @@
+//! Graceful shutdown of the threaded relay on SIGINT or SIGTERM.
+//!
+//! On the first signal the relay stops accepting new clients, and stops reading from each source client once it has
+//! relayed the complete messages already received from it. Destination clients are then sent everything still queued
+//! for them, until the shutdown timeout, before the relay exits. A second signal exits straight away.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{info, warn};
+
+use crate::transport::{ListenAddr, Stream};
+
+/// How long to wait when connecting to a listener to wake its accept loop.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shutdown state shared by the accept loops and the threads serving source clients.
+pub struct GracefulShutdown {
+    requested: AtomicBool,
+    /// When the relay gives up on draining and exits. Set when shutdown is requested.
+    deadline: Mutex<Option<Instant>>,
+    timeout: Duration,
+    /// Connections of the source sessions in progress, by session ID, so their reading side can be shut down.
+    sessions: Mutex<HashMap<u64, Stream>>,
+    /// Signalled when a source session ends.
+    session_ended: Condvar,
+    /// Addresses of the listeners, connected to once to wake their accept loops.
+    listeners: Mutex<Vec<ListenAddr>>,
+}
+
+/// A source session in progress, which stops being tracked when dropped.
+pub struct Tracked {
+    shutdown: Arc<GracefulShutdown>,
+    session: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.shutdown.sessions.lock().unwrap().remove(&self.session);
+        self.shutdown.session_ended.notify_all();
+    }
+}
+
+impl GracefulShutdown {
+    /// Sets up shutdown on SIGINT and SIGTERM. Draining the relay may take up to `timeout` once a signal is received.
+    pub fn install(timeout: Duration) -> io::Result<Arc<GracefulShutdown>> {
+        let shutdown = Arc::new(GracefulShutdown {
+            requested: AtomicBool::new(false),
+            deadline: Mutex::new(None),
+            timeout,
+            sessions: Mutex::new(HashMap::new()),
+            session_ended: Condvar::new(),
+            listeners: Mutex::new(Vec::new()),
+        });
+
+        // Thread to wait for signals. The first starts the shutdown, and a second one gives up on draining.
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handler = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if handler.is_requested() {
+                    warn!(signal, "second signal received, exiting without draining");
+                    process::exit(1);
+                }
+                info!(signal, timeout_ms = handler.timeout.as_millis() as u64, "shutting down");
+                handler.request();
+            }
+        });
+        Ok(shutdown)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Time left before the relay gives up on draining. Zero if shutdown has not been requested.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.lock().unwrap().map_or(Duration::ZERO, |deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Registers a listener, whose accept loop must check `is_requested` after every accepted connection.
+    pub fn add_listener(&self, addr: ListenAddr) {
+        self.listeners.lock().unwrap().push(addr);
+    }
+
+    /// Starts tracking a newly accepted source session, so it can be stopped on shutdown. Returns `None` if the relay
+    /// is already shutting down, or the connection cannot be tracked, in which case the session should end.
+    pub fn track(self: &Arc<Self>, session: u64, stream: &Stream) -> Option<Tracked> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error = %e, "failed to set up source session");
+                return None;
+            }
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        if self.is_requested() {
+            return None;
+        }
+        sessions.insert(session, stream);
+        Some(Tracked { shutdown: Arc::clone(self), session })
+    }
+
+    /// Waits for every source session to end, or for the deadline to pass. Returns the number still running.
+    pub fn wait_sessions(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        loop {
+            let remaining = self.remaining();
+            if sessions.is_empty() || remaining.is_zero() {
+                return sessions.len();
+            }
+            sessions = self.session_ended.wait_timeout(sessions, remaining).unwrap().0;
+        }
+    }
+
+    /// Starts the shutdown: stops reading from the source clients and wakes the accept loops so they stop accepting.
+    fn request(&self) {
+        {
+            // Holding the lock means no session can start being tracked without being stopped.
+            let sessions = self.sessions.lock().unwrap();
+            *self.deadline.lock().unwrap() = Some(Instant::now() + self.timeout);
+            self.requested.store(true, Ordering::Relaxed);
+
+            // Whatever the source already sent can still be read, so complete messages received before the shutdown
+            // are relayed, and the session then ends as if the source had disconnected.
+            for stream in sessions.values() {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+
+        for addr in self.listeners.lock().unwrap().iter() {
+            let woken = match addr {
+                ListenAddr::Tcp(addr) => {
+                    // A listener on every interface is reached through the loopback address of the same family.
+                    let ip = match addr.ip() {
+                        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                        ip => ip,
+                    };
+                    TcpStream::connect_timeout(&SocketAddr::new(ip, addr.port()), WAKE_TIMEOUT).map(drop)
+                }
+                ListenAddr::Unix(path) => UnixStream::connect(path).map(drop),
+            };
+            if let Err(e) = woken {
+                warn!(error = %e, %addr, "failed to wake listener");
+            }
+        }
+    }
+}
@@ -0,0 +1,592 @@
+//! Accepting source clients and decoding the messages they send.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctmp::{CtmpMessage, Decoder, FrameError};
+use rustls::ServerConfig;
+use tracing::{field, info, info_span, warn};
+
+use crate::acl::AccessList;
+use crate::arbiter::Arbiter;
+use crate::auth::{self, Authenticator, SourceAuth};
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::destination::Destinations;
+use crate::journal::Journal;
+use crate::multicast::Multicast;
+use crate::metrics::{self, Metrics};
+use crate::shutdown::GracefulShutdown;
+use crate::subscription::{self, Channel, SourceHello};
+use crate::transport::{AcceptBackoff, Listener, Peer, Stream};
+
+/// Line sent to a source client disconnected to serve a newly connected source.
+pub const PREEMPTED_REPLY: &str = "CTMP ERR preempted by another source\n";
+
+/// Reason given to a source client whose address is blocked after too many failed authentication attempts.
+pub const BLOCKED_REASON: &str = "too many failed authentication attempts";
+
+/// What happens when a source client connects in single source mode while another source client is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceConflict {
+    /// Turn the new source away with a reason, leaving the current source connected.
+    Reject,
+    /// Disconnect the current source and serve the new one.
+    Preempt,
+    /// Serve the new source once the current source disconnects, turning it away if that takes too long.
+    /// Waiting sources are served in the order they connected.
+    Queue,
+}
+
+impl FromStr for SourceConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SourceConflict, String> {
+        match s {
+            "reject" => Ok(SourceConflict::Reject),
+            "preempt" => Ok(SourceConflict::Preempt),
+            "queue" => Ok(SourceConflict::Queue),
+            _ => Err(format!("unknown source conflict policy '{}', expected reject, preempt or queue", s)),
+        }
+    }
+}
+
+impl fmt::Display for SourceConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceConflict::Reject => "reject",
+            SourceConflict::Preempt => "preempt",
+            SourceConflict::Queue => "queue",
+        })
+    }
+}
+
+/// The source session currently being served in single source mode.
+struct ActiveSource {
+    session: u64,
+    /// Ends the session, used to preempt it.
+    stop: Box<dyn Fn() + Send>,
+    /// Set once the session has been asked to stop, so it is only preempted once.
+    stopping: bool,
+}
+
+struct SlotState {
+    active: Option<ActiveSource>,
+    /// Sessions waiting for the slot under the queue policy, in the order they connected.
+    waiting: VecDeque<u64>,
+}
+
+/// The single slot for a source client in single source mode. A session must acquire the slot before reading from its
+/// source, so that only one source is relayed at a time.
+pub struct SourceSlot {
+    state: Mutex<SlotState>,
+    /// Signalled when the active session releases the slot, or a waiting session gives up.
+    released: Condvar,
+    policy: SourceConflict,
+    wait_timeout: Duration,
+}
+
+impl SourceSlot {
+    pub fn new(policy: SourceConflict, wait_timeout: Duration) -> SourceSlot {
+        SourceSlot {
+            state: Mutex::new(SlotState { active: None, waiting: VecDeque::new() }),
+            released: Condvar::new(),
+            policy,
+            wait_timeout,
+        }
+    }
+
+    /// Acquires the slot for a session, applying the conflict policy if another session holds it. `stop` is called to
+    /// end the session if a later source preempts it. Blocks while waiting for the slot, and returns the reason to
+    /// give the source if it is turned away.
+    pub fn acquire(&self, session: u64, stop: Box<dyn Fn() + Send>, metrics: &Metrics) -> Result<(), &'static str> {
+        let mut state = self.state.lock().unwrap();
+        match self.policy {
+            SourceConflict::Reject => {
+                if state.active.is_some() {
+                    return Err("another source is connected");
+                }
+            }
+            SourceConflict::Preempt => {
+                while let Some(active) = state.active.as_mut() {
+                    if !active.stopping {
+                        info!(preempted_session = active.session, "preempting current source");
+                        metrics::add(&metrics.sources_preempted, 1);
+                        active.stopping = true;
+                        (active.stop)();
+                    }
+                    state = self.released.wait(state).unwrap();
+                }
+            }
+            SourceConflict::Queue => {
+                let deadline = Instant::now() + self.wait_timeout;
+                state.waiting.push_back(session);
+                if state.active.is_some() || state.waiting.len() > 1 {
+                    info!(queued = state.waiting.len(), timeout_ms = self.wait_timeout.as_millis() as u64, "waiting for the current source to disconnect");
+                }
+                while state.active.is_some() || state.waiting.front() != Some(&session) {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        state.waiting.retain(|waiting| *waiting != session);
+                        // The next waiting session may now be at the front.
+                        self.released.notify_all();
+                        return Err("timed out waiting for the current source to disconnect");
+                    }
+                    state = self.released.wait_timeout(state, remaining).unwrap().0;
+                }
+                state.waiting.pop_front();
+            }
+        }
+        state.active = Some(ActiveSource { session, stop, stopping: false });
+        Ok(())
+    }
+
+    /// Releases the slot once a session has ended.
+    pub fn release(&self, session: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.active.as_ref().is_some_and(|active| active.session == session) {
+            state.active = None;
+            self.released.notify_all();
+        }
+    }
+}
+
+/// A source session being relayed, as shown by the admin interface.
+pub struct SourceInfo {
+    pub session: u64,
+    pub peer: Peer,
+    pub channel: Channel,
+    /// How long the session has been relayed.
+    pub connected: Duration,
+}
+
+/// Registry of the source sessions currently being relayed, in either source mode. Sessions waiting for the slot in
+/// single source mode are not included.
+#[derive(Default)]
+pub struct ConnectedSources {
+    sessions: Mutex<BTreeMap<u64, (Peer, Channel, Instant)>>,
+}
+
+/// A registered source session, which is removed from the registry when dropped.
+struct Registered<'a> {
+    sources: &'a ConnectedSources,
+    session: u64,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        self.sources.sessions.lock().unwrap().remove(&self.session);
+    }
+}
+
+impl ConnectedSources {
+    fn register(&self, session: u64, peer: &Peer, channel: &Channel) -> Registered<'_> {
+        self.sessions.lock().unwrap().insert(session, (peer.clone(), Arc::clone(channel), Instant::now()));
+        Registered { sources: self, session }
+    }
+
+    /// Details of every source session being relayed, by session ID.
+    pub fn list(&self) -> Vec<SourceInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(session, (peer, channel, connected))| SourceInfo { session: *session, peer: peer.clone(), channel: Arc::clone(channel), connected: connected.elapsed() })
+            .collect()
+    }
+}
+
+/// Everything the threads serving source clients share.
+pub struct SourceContext {
+    pub config: Config,
+    pub destinations: Arc<Destinations>,
+    pub metrics: Arc<Metrics>,
+    /// TLS settings of the source listener, if enabled.
+    pub tls: Option<Arc<ServerConfig>>,
+    pub auth: Authenticator,
+    /// Addresses source clients may connect from.
+    pub acl: AccessList,
+    /// Journal every accepted message is appended to, if enabled.
+    pub journal: Option<Arc<Journal>>,
+    /// Multicast group every relayed message is published to, if enabled.
+    pub multicast: Option<Arc<Multicast>>,
+    pub shutdown: Arc<GracefulShutdown>,
+    /// Source sessions being relayed, listed by the admin interface.
+    pub sources: Arc<ConnectedSources>,
+}
+
+/// Function to serve one source client at a time. Runs until the relay shuts down, so a new source client can connect
+/// after the current client disconnects. Each connection is handled on its own thread, so a source connecting while
+/// another is connected is dealt with straight away according to the conflict policy.
+pub fn serve_single(listeners: Vec<Listener>, context: Arc<SourceContext>) {
+    let config = &context.config;
+    let slot = SourceSlot::new(config.source_conflict, Duration::from_millis(config.source_wait_timeout_ms));
+
+    let session_context = Arc::clone(&context);
+    accept(listeners, &context, move |source_stream, source_addr, session| {
+        let context = &session_context;
+        let metrics = &context.metrics;
+        let Some(_tracked) = context.shutdown.track(session, &source_stream) else {
+            return;
+        };
+        let Some((mut source, channel)) = connect(source_stream, &source_addr, context) else {
+            return;
+        };
+
+        // Preempting a session shuts down the reading side of its connection, which ends its read loop. The source
+        // is then told why before the connection is closed.
+        let preempted = Arc::new(AtomicBool::new(false));
+        let stop: Box<dyn Fn() + Send> = match source.get_ref().stream().try_clone() {
+            Ok(stream) => {
+                let preempted = Arc::clone(&preempted);
+                Box::new(move || {
+                    preempted.store(true, Ordering::Relaxed);
+                    let _ = stream.shutdown(Shutdown::Read);
+                })
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to set up source session");
+                return;
+            }
+        };
+        if let Err(reason) = slot.acquire(session, stop, metrics) {
+            reject(source.into_inner(), reason, metrics);
+            return;
+        }
+
+        let _registered = context.sources.register(session, &source_addr, &channel);
+        run_session(&mut source, metrics, &context.config, |message| {
+            if let Some(journal) = &context.journal {
+                journal.append(&channel, &message);
+            }
+            // Broadcast message to destination clients. Each destination's writer thread sends it independently.
+            metrics::add(&metrics.frames_relayed, 1);
+            if let Some(multicast) = &context.multicast {
+                multicast.publish(&channel, &message);
+            }
+            context.destinations.broadcast(&channel, message);
+        });
+        let connection = source.get_mut();
+        if preempted.load(Ordering::Relaxed) {
+            let _ = connection.write_all(PREEMPTED_REPLY.as_bytes());
+        }
+        connection.shutdown();
+        slot.release(session);
+    });
+
+    let running = context.shutdown.wait_sessions();
+    if running > 0 {
+        warn!(running, "shutdown timeout passed before the source sessions ended");
+    }
+}
+
+/// Function to serve several source clients concurrently, each on its own thread, until the relay shuts down. Their
+/// messages are interleaved by the arbiter at message boundaries according to the configured fairness policy.
+pub fn serve_multi(listeners: Vec<Listener>, context: Arc<SourceContext>) {
+    let config = &context.config;
+    let arbiter = Arc::new(Arbiter::new(config.source_queue_depth, config.fairness, config.max_payload));
+
+    // Thread relaying the messages chosen by the arbiter to the destination clients, until the arbiter is closed and
+    // empty. It reports when it finishes, so the shutdown can wait for it with a timeout.
+    let (finished, relay_finished) = mpsc::channel::<()>();
+    {
+        let arbiter = Arc::clone(&arbiter);
+        let context = Arc::clone(&context);
+        thread::spawn(move || {
+            while let Some((channel, message)) = arbiter.next() {
+                metrics::add(&context.metrics.frames_relayed, 1);
+                if let Some(multicast) = &context.multicast {
+                    multicast.publish(&channel, &message);
+                }
+                context.destinations.broadcast(&channel, message);
+            }
+            let _ = finished.send(());
+        });
+    }
+
+    let session_arbiter = Arc::clone(&arbiter);
+    let session_context = Arc::clone(&context);
+    accept(listeners, &context, move |source_stream, source_addr, session| {
+        let (arbiter, context) = (&session_arbiter, &session_context);
+        let metrics = &context.metrics;
+        let Some(_tracked) = context.shutdown.track(session, &source_stream) else {
+            return;
+        };
+        let Some((mut source, channel)) = connect(source_stream, &source_addr, context) else {
+            return;
+        };
+        if !arbiter.register(session, context.config.max_sources) {
+            reject(source.into_inner(), "maximum number of sources connected", metrics);
+            return;
+        }
+
+        let _registered = context.sources.register(session, &source_addr, &channel);
+
+        // Messages are journaled as they are accepted, rather than when the arbiter relays them, so their receive
+        // time is accurate even when the source's queue is backed up.
+        run_session(&mut source, metrics, &context.config, |message| {
+            if let Some(journal) = &context.journal {
+                journal.append(&channel, &message);
+            }
+            arbiter.push(session, &channel, message);
+        });
+        source.get_mut().shutdown();
+        arbiter.unregister(session);
+    });
+
+    let running = context.shutdown.wait_sessions();
+    arbiter.close();
+    if running > 0 || relay_finished.recv_timeout(context.shutdown.remaining()).is_err() {
+        warn!(running, "shutdown timeout passed before the source sessions ended and their messages were relayed");
+    }
+}
+
+/// Function to accept source clients on every listener until the relay shuts down. Each client permitted by the access
+/// list is given a session ID and served by `serve` on its own thread. Returns once every listener has stopped accepting.
+fn accept(listeners: Vec<Listener>, context: &SourceContext, serve: impl Fn(Stream, Peer, u64) + Send + Sync + 'static) {
+    // Errors are contained to the source session they happen in, so destination clients stay connected.
+    // Each source connection gets a session ID, which is attached to everything logged during the session.
+    let next_session = AtomicU64::new(1);
+    let serve = Arc::new(serve);
+    thread::scope(|scope| {
+        for listener in listeners {
+            let next_session = &next_session;
+            let serve = Arc::clone(&serve);
+            let mut backoff = AcceptBackoff::new();
+            scope.spawn(move || loop {
+                let (source_stream, source_addr) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        thread::sleep(backoff.failed(&e, "source"));
+                        continue;
+                    }
+                };
+                backoff.succeeded();
+                if context.shutdown.is_requested() {
+                    break;
+                }
+                if !context.acl.admit(&source_addr, "source", &context.metrics) {
+                    continue;
+                }
+                let session = next_session.fetch_add(1, Ordering::Relaxed);
+
+                let serve = Arc::clone(&serve);
+                thread::spawn(move || {
+                    let _span = info_span!("source", session, peer = %source_addr, channel = field::Empty).entered();
+                    serve(source_stream, source_addr, session);
+                });
+            });
+        }
+    });
+}
+
+/// Function to set up a newly accepted source client: the TLS handshake if enabled, then the source handshake.
+/// Returns `None` if either fails, after logging why.
+fn connect(source_stream: Stream, source_addr: &Peer, context: &SourceContext) -> Option<(BufReader<Connection>, Channel)> {
+    let connection = match Connection::accept(source_stream, context.tls.as_ref()) {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!(error = %e, "source TLS handshake failed");
+            return None;
+        }
+    };
+
+    // Buffered so the start of the stream can be checked for a hello line without losing any messages.
+    let mut source = BufReader::new(connection);
+    match handshake(&mut source, source_addr.ip(), context) {
+        Ok(channel) => {
+            tracing::Span::current().record("channel", &*channel);
+            Some((source, channel))
+        }
+        Err(e) => {
+            warn!(error = %e, "source handshake failed");
+            source.get_mut().shutdown();
+            None
+        }
+    }
+}
+
+/// Function to perform the handshake with a source client: authentication if enabled, and the hello line naming its
+/// channel. `peer` is the source's IP address, used to block addresses with too many failed authentication attempts,
+/// or `None` for a client of a Unix domain socket. Returns the channel, or an error once the source has been told why
+/// it was turned away.
+fn handshake(source: &mut BufReader<Connection>, peer: Option<IpAddr>, context: &SourceContext) -> io::Result<Channel> {
+    let auth = &context.auth;
+    let metrics = &context.metrics;
+    let authenticate = auth.method() != SourceAuth::None;
+    if is_blocked(peer, auth, metrics) {
+        return Err(refuse(source, BLOCKED_REASON));
+    }
+    if authenticate {
+        // An unauthenticated client is only given a limited time to prove itself.
+        source.get_ref().stream().set_read_timeout(Some(auth.timeout()))?;
+    }
+
+    let nonce = auth.challenge()?;
+    if let Some(nonce) = &nonce {
+        source.get_mut().write_all(auth::challenge_line(nonce).as_bytes())?;
+    }
+
+    let line = subscription::read_source_hello(source)?;
+    let hello = check_hello(line.as_deref(), nonce.as_deref(), peer, auth, metrics).map_err(|reason| refuse(source, &reason))?;
+    if authenticate {
+        source.get_ref().stream().set_read_timeout(None)?;
+    }
+
+    if line.is_some() {
+        source.get_mut().write_all(subscription::source_reply(&hello.channel).as_bytes())?;
+    }
+    Ok(hello.channel)
+}
+
+/// Function to check a source client's hello line, if it sent one, and its credentials if authentication is enabled.
+/// Shared by the threaded and async runtimes. `nonce` is the challenge sent to the source for HMAC authentication, and
+/// `peer` its IP address, used to block addresses with too many failed attempts. Returns the reason to refuse the
+/// source with if the hello is invalid or authentication fails.
+pub fn check_hello(line: Option<&str>, nonce: Option<&[u8]>, peer: Option<IpAddr>, auth: &Authenticator, metrics: &Metrics) -> Result<SourceHello, String> {
+    let hello = match line {
+        Some(line) => SourceHello::from_line(line)?,
+        None => SourceHello::default(),
+    };
+
+    if let Err(reason) = auth.verify(nonce, hello.token.as_deref(), hello.auth.as_deref()) {
+        metrics::add(&metrics.source_auth_failures, 1);
+        let blocked = peer.is_some_and(|peer| auth.record_failure(peer));
+        warn!(reason, blocked, "source authentication failed");
+        return Err(reason.to_string());
+    }
+    if auth.method() != SourceAuth::None {
+        if let Some(peer) = peer {
+            auth.record_success(peer);
+        }
+        info!(method = %auth.method(), "source authenticated");
+    }
+    Ok(hello)
+}
+
+/// Function to check whether a source client's address is blocked after too many failed authentication attempts,
+/// counting it if so. Shared by the threaded and async runtimes.
+pub fn is_blocked(peer: Option<IpAddr>, auth: &Authenticator, metrics: &Metrics) -> bool {
+    let blocked = auth.method() != SourceAuth::None && peer.is_some_and(|peer| auth.is_blocked(peer));
+    if blocked {
+        metrics::add(&metrics.source_auth_blocked, 1);
+    }
+    blocked
+}
+
+/// Function to tell a source client why its handshake failed, returning the error to end the session with.
+fn refuse(source: &mut BufReader<Connection>, reason: &str) -> io::Error {
+    let _ = source.get_mut().write_all(subscription::error_reply(reason).as_bytes());
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// Function to turn a source client away, telling it why in a `CTMP ERR <reason>` line before closing the connection.
+fn reject(mut connection: Connection, reason: &str, metrics: &Metrics) {
+    warn!(reason, "source rejected");
+    metrics::add(&metrics.sources_rejected, 1);
+    let _ = connection.write_all(subscription::error_reply(reason).as_bytes());
+    connection.shutdown();
+}
+
+/// Function to run a single source session, logging when it starts and ends.
+fn run_session(source: &mut BufReader<Connection>, metrics: &Metrics, config: &Config, deliver: impl FnMut(CtmpMessage)) {
+    info!("source connected");
+    metrics::add(&metrics.sources_connected, 1);
+    match handle_source(source, metrics, config, deliver) {
+        Ok(()) => info!("source disconnected"),
+        Err(e) => warn!(error = %e, "source disconnected with error"),
+    }
+    metrics::sub(&metrics.sources_connected);
+}
+
+/// Function to handle the messages sent by a source client, passing each accepted message to `deliver`. Function is
+/// exited when the source disconnects, or with an error if reading from the source fails.
+fn handle_source(source: &mut BufReader<Connection>, metrics: &Metrics, config: &Config, mut deliver: impl FnMut(CtmpMessage)) -> io::Result<()> {
+    let mut frames = FrameProcessor::new(metrics, config);
+    let mut read_buffer = [0u8; 1024];
+    loop {
+        // Only read as much as the decoder's buffer has space for, so a source cannot make it grow without bound.
+        let read_len = read_buffer.len().min(frames.space());
+        let bytes_read = source.read(&mut read_buffer[..read_len])?;
+        if bytes_read == 0 { // If the source disconnects, exit the loop.
+            break;
+        }
+        frames.push(&read_buffer[..bytes_read], &mut deliver);
+    }
+    frames.finish();
+    Ok(())
+}
+
+/// Decoding of the bytes read from a source client into messages, shared by the threaded and async runtimes so that
+/// only reading from the source differs between them.
+pub struct FrameProcessor<'a> {
+    decoder: Decoder,
+    metrics: &'a Metrics,
+    config: &'a Config,
+    /// Number of messages rejected for stating a payload longer than the maximum.
+    oversized: u64,
+}
+
+impl<'a> FrameProcessor<'a> {
+    pub fn new(metrics: &'a Metrics, config: &'a Config) -> FrameProcessor<'a> {
+        let decoder = Decoder::new().with_max_payload(config.max_payload).with_max_buffer(config.max_buffer).with_validation(config.validation);
+        FrameProcessor { decoder, metrics, config, oversized: 0 }
+    }
+
+    /// Number of bytes that can be pushed without the decoder's buffer going over the maximum.
+    pub fn space(&self) -> usize {
+        self.decoder.space()
+    }
+
+    /// Processes bytes read from the source, passing each complete message that is accepted to `deliver`. Invalid
+    /// messages and messages of a version not accepted from sources are dropped, logged and counted.
+    pub fn push(&mut self, bytes: &[u8], mut deliver: impl FnMut(CtmpMessage)) {
+        let metrics = self.metrics;
+        metrics::add(&metrics.bytes_in, bytes.len() as u64);
+        self.decoder.push(bytes);
+
+        // Loop to process all of the complete messages in the buffer.
+        while let Some(frame) = self.decoder.next_frame() {
+            let message = match frame {
+                Ok(message) => message,
+                Err(e) => { // The decoder has already resynchronised, so carry on with any further frames in the buffer.
+                    if let FrameError::PayloadTooLarge { .. } = e {
+                        self.oversized += 1;
+                    }
+                    metrics.record_frame_error(&e);
+                    warn!(reason = %e, "message dropped");
+                    continue;
+                }
+            };
+
+            let version = message.header().version();
+            if !self.config.source_versions.contains(&version) { // Version is not accepted from sources.
+                metrics::add(&metrics.version_rejections, 1);
+                warn!(%version, length = message.header().length, "message dropped, CTMP version is not accepted");
+                continue;
+            }
+
+            // Only accepted frames are counted as received, so the drop counters account for every other frame.
+            metrics::add(&metrics.frames_received, 1);
+            deliver(message);
+        }
+        metrics::add(&metrics.garbage_bytes, self.decoder.take_discarded());
+    }
+
+    /// Logs what was dropped over the session once the source has disconnected.
+    pub fn finish(self) {
+        if self.oversized > 0 {
+            warn!(oversized = self.oversized, max_payload = self.config.max_payload, "source sent messages over the maximum payload length");
+        }
+        if self.decoder.buffered() > 0 {
+            info!(bytes = self.decoder.buffered(), "source stopped partway through a message, partial message discarded");
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Handshakes letting destination clients declare which messages they want to receive, and source clients name the
+//! channel they send on.
+//!
+//! After connecting, a destination client may send a single hello line of space separated `key=value` settings:
+//!
+//! ```text
+//! CTMP versions=1,2 channels=prices,trades filter=!sensitive,len>=100 history=100
+//! ```
+//!
+//! The relay replies with `CTMP OK ...` stating the negotiated settings, or `CTMP ERR <reason>` before closing the
+//! connection. Destination clients that send nothing within the hello timeout receive every version on the default
+//! channel, as before.
+//!
+//! A source client may send a hello line naming its channel before its first message, e.g. `CTMP channel=prices`,
+//! and is answered in the same way. Sources that send no hello send on the default channel. If source authentication
+//! is enabled the hello is required, and also carries the source's credentials (see the `auth` module).
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ctmp::{CtmpMessage, Version};
+
+use crate::connection::Connection;
+use crate::filter::Filter;
+use crate::history::HistoryRequest;
+
+/// Maximum length of a hello line, so a client cannot make the relay buffer an endless line.
+pub const MAX_HELLO_LEN: u64 = 1024;
+
+/// First byte of a source client's hello line.
+pub const SOURCE_HELLO_START: u8 = b'C';
+
+/// Name of a channel. Every message is relayed on the channel of the source client that sent it.
+pub type Channel = Arc<str>;
+
+/// Channel of source clients that do not name one, and of destination clients that do not subscribe to any.
+pub const DEFAULT_CHANNEL: &str = "default";
+
+/// Channel name a destination client can subscribe to in order to receive every channel.
+pub const ALL_CHANNELS: &str = "*";
+
+/// Maximum length of a channel name.
+pub const MAX_CHANNEL_LEN: usize = 64;
+
+/// What a destination client has subscribed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    /// Protocol versions of the messages delivered to the destination.
+    pub versions: Vec<Version>,
+    /// Channels of the messages delivered to the destination, or `*` for every channel.
+    pub channels: Vec<Channel>,
+    /// Conditions on the header and payload of the messages delivered to the destination. See the `filter` module.
+    pub filter: Filter,
+    /// How much of the relay's recent history to replay before live traffic. See the `history` module.
+    pub history: HistoryRequest,
+}
+
+impl Subscription {
+    /// Subscription of a destination client that did not send a hello: every version the relay accepts, on the default
+    /// channel.
+    pub fn new(relay_versions: &[Version]) -> Subscription {
+        Subscription { versions: relay_versions.to_vec(), channels: vec![Channel::from(DEFAULT_CHANNEL)], filter: Filter::default(), history: HistoryRequest::All }
+    }
+
+    /// Parses a hello line, negotiating the requested settings against what the relay supports.
+    pub fn from_hello(line: &str, relay_versions: &[Version]) -> Result<Subscription, String> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("CTMP") {
+            return Err("hello must start with CTMP".to_string());
+        }
+
+        let mut subscription = Subscription::new(relay_versions);
+        for word in words {
+            let (key, value) = word.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", word))?;
+            match key {
+                "channels" => {
+                    subscription.channels = value
+                        .split(',')
+                        .map(|name| if name == ALL_CHANNELS { Ok(Channel::from(name)) } else { parse_channel(name) })
+                        .collect::<Result<_, _>>()?;
+                }
+                "filter" => subscription.filter = value.parse()?,
+                "history" => subscription.history = value.parse()?,
+                "versions" => {
+                    let requested = value.split(',').map(str::parse).collect::<Result<Vec<Version>, String>>()?;
+                    // Only versions accepted from sources can ever be delivered.
+                    subscription.versions = requested.into_iter().filter(|v| relay_versions.contains(v)).collect();
+                    if subscription.versions.is_empty() {
+                        return Err(format!("none of the requested versions are supported, the relay accepts {}", join(relay_versions)));
+                    }
+                }
+                _ => return Err(format!("unknown setting '{}'", key)),
+            }
+        }
+
+        Ok(subscription)
+    }
+
+    /// Returns true if the message, relayed on the given channel, should be delivered to the destination.
+    pub fn accepts(&self, channel: &str, message: &CtmpMessage) -> bool {
+        self.versions.contains(&message.header().version())
+            && self.channels.iter().any(|subscribed| &**subscribed == ALL_CHANNELS || &**subscribed == channel)
+            && self.filter.matches(message)
+    }
+
+    /// Reply line confirming the negotiated settings. The filter and history are only stated if they were set.
+    pub fn reply(&self) -> String {
+        let mut reply = format!("CTMP OK versions={} channels={}", join(&self.versions), self.channels.join(","));
+        if !self.filter.is_empty() {
+            reply.push_str(&format!(" filter={}", self.filter));
+        }
+        if self.history != HistoryRequest::All {
+            reply.push_str(&format!(" history={}", self.history));
+        }
+        reply.push('\n');
+        reply
+    }
+}
+
+/// Settings given in a source client's hello line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceHello {
+    /// Channel the source sends on.
+    pub channel: Channel,
+    /// Shared secret, for token authentication. See the `auth` module.
+    pub token: Option<String>,
+    /// Answer to the relay's challenge, for HMAC authentication.
+    pub auth: Option<String>,
+}
+
+impl Default for SourceHello {
+    /// Settings of a source client that did not send a hello.
+    fn default() -> SourceHello {
+        SourceHello { channel: Channel::from(DEFAULT_CHANNEL), token: None, auth: None }
+    }
+}
+
+impl SourceHello {
+    /// Parses a source client's hello line.
+    pub fn from_line(line: &str) -> Result<SourceHello, String> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("CTMP") {
+            return Err("hello must start with CTMP".to_string());
+        }
+
+        let mut hello = SourceHello::default();
+        for word in words {
+            let (key, value) = word.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", word))?;
+            match key {
+                "channel" => hello.channel = parse_channel(value)?,
+                "token" => hello.token = Some(value.to_string()),
+                "auth" => hello.auth = Some(value.to_string()),
+                _ => return Err(format!("unknown setting '{}'", key)),
+            }
+        }
+        Ok(hello)
+    }
+}
+
+/// Function to check a channel name. Names are limited to letters, digits, '-', '_' and '.', so they can be listed in
+/// hello lines and logged as they are.
+fn parse_channel(name: &str) -> Result<Channel, String> {
+    let valid = name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if name.is_empty() || name.len() > MAX_CHANNEL_LEN || !valid {
+        return Err(format!("invalid channel name '{}', expected 1 to {} letters, digits, '-', '_' or '.'", name, MAX_CHANNEL_LEN));
+    }
+    Ok(Channel::from(name))
+}
+
+/// Function to perform the handshake with a newly connected destination client.
+/// Returns an error if the client sent an invalid hello, after telling it why.
+pub fn handshake(connection: &mut Connection, relay_versions: &[Version], timeout: Duration) -> io::Result<Subscription> {
+    connection.stream().set_read_timeout(Some(timeout))?;
+    let hello = read_hello(&mut *connection);
+    connection.stream().set_read_timeout(None)?;
+
+    match negotiate(hello?.as_deref(), relay_versions) {
+        Ok((subscription, reply)) => {
+            if let Some(reply) = reply {
+                connection.write_all(reply.as_bytes())?;
+            }
+            Ok(subscription)
+        }
+        Err(reason) => {
+            let _ = connection.write_all(error_reply(&reason).as_bytes());
+            Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+    }
+}
+
+/// Function to settle a destination client's subscription from its hello line, if it sent one. Shared by the threaded
+/// and async runtimes. Returns the subscription and the reply to send, which is `None` if the client sent no hello, or
+/// the reason the client is refused.
+pub fn negotiate(line: Option<&str>, relay_versions: &[Version]) -> Result<(Subscription, Option<String>), String> {
+    let Some(line) = line else {
+        return Ok((Subscription::new(relay_versions), None));
+    };
+    let subscription = Subscription::from_hello(line, relay_versions)?;
+    let reply = subscription.reply();
+    Ok((subscription, Some(reply)))
+}
+
+/// Line telling a client why it is being turned away, before the connection is closed.
+pub fn error_reply(reason: &str) -> String {
+    format!("CTMP ERR {}\n", reason)
+}
+
+/// Function to read a source client's hello line. Returns `None` if the client did not send one: a source client's
+/// messages start with the magic byte 0xCC, while a hello starts with 'C'. Any messages read along with the hello are
+/// left in `reader` for the decoder.
+pub fn read_source_hello(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    if reader.fill_buf()?.first() != Some(&SOURCE_HELLO_START) {
+        return Ok(None);
+    }
+
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_HELLO_LEN).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hello line is incomplete or too long"));
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Reply line confirming a source client's channel.
+pub fn source_reply(channel: &str) -> String {
+    format!("CTMP OK channel={}\n", channel)
+}
+
+/// Function to read the hello line. Returns `None` if the client did not send anything before the read timed out.
+fn read_hello(stream: impl Read) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    match BufReader::new(stream.take(MAX_HELLO_LEN)).read_line(&mut line) {
+        Ok(_) => {}
+        Err(e) if line.is_empty() && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    if line.is_empty() { // The client closed its sending side without a hello.
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "hello line is incomplete or too long"));
+    }
+    Ok(Some(line))
+}
+
+/// Function to format a list of versions as a comma separated list.
+pub fn join(versions: &[Version]) -> String {
+    versions.iter().map(Version::to_string).collect::<Vec<_>>().join(",")
+}
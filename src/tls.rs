@@ -0,0 +1,154 @@
+//! TLS for the source and destination listeners, using rustls.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::transport::Stream;
+
+/// How long the whole TLS handshake may take, so a client that never completes the handshake, or sends it a byte at a
+/// time, does not hold on to its thread.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A TLS session with a client, over its TCP or Unix domain socket connection.
+pub type TlsStream = StreamOwned<ServerConnection, Stream>;
+
+/// Function to load the TLS settings for a listener from its certificate chain and private key, both PEM files.
+/// Returns `None` if TLS is not enabled for the listener. The config is validated to have both paths or neither.
+pub fn load(cert: Option<&PathBuf>, key: Option<&PathBuf>) -> io::Result<Option<Arc<ServerConfig>>> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => server_config(cert, key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Function to build the rustls server settings from a certificate chain and private key.
+fn server_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidInput, format!("could not load {}: {}", path.display(), e));
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert, &e))?;
+    if certs.is_empty() {
+        return Err(invalid(cert, &"no certificates found"));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, &e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, private_key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid TLS certificate or key: {}", e)))?;
+    Ok(Arc::new(config))
+}
+
+/// Function to perform the TLS handshake with a newly accepted client.
+pub fn handshake(config: &Arc<ServerConfig>, mut stream: Stream) -> io::Result<TlsStream> {
+    let mut connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+
+    let result = complete_handshake(&mut connection, &mut stream, Instant::now() + HANDSHAKE_TIMEOUT);
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    result?;
+
+    Ok(StreamOwned::new(connection, stream))
+}
+
+/// Function to perform the handshake, failing with `TimedOut` once the deadline has passed. A single `complete_io` call
+/// can read and write several times, so the time left is applied before every read and write rather than per call.
+fn complete_handshake(connection: &mut ServerConnection, stream: &mut Stream, deadline: Instant) -> io::Result<()> {
+    let mut stream = DeadlineStream { stream, deadline };
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(())
+}
+
+/// A stream whose reads and writes fail once a deadline has passed.
+struct DeadlineStream<'a> {
+    stream: &'a mut Stream,
+    deadline: Instant,
+}
+
+impl DeadlineStream<'_> {
+    /// Time left until the deadline, or a `TimedOut` error if it has passed.
+    fn time_left(&self) -> io::Result<Duration> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"));
+        }
+        Ok(left)
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.time_left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.time_left()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    /// Function to start a handshake over a socket pair, returning the connection and both ends of the socket.
+    fn start() -> (ServerConnection, Stream, UnixStream) {
+        let config = server_config(&Path::new(FIXTURES).join("relay.crt"), &Path::new(FIXTURES).join("relay.key")).unwrap();
+        let (server, client) = UnixStream::pair().unwrap();
+        (ServerConnection::new(config).unwrap(), Stream::Unix(server), client)
+    }
+
+    #[test]
+    fn times_out_silent_client() {
+        let (mut connection, mut stream, _client) = start();
+        let started = Instant::now();
+        let error = complete_handshake(&mut connection, &mut stream, Instant::now() + Duration::from_millis(200)).unwrap_err();
+        assert!(matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn deadline_covers_whole_handshake() {
+        // A client trickling a byte at a time, each well within the time allowed for the whole handshake, of a record
+        // long enough to take seconds to send.
+        let (mut connection, mut stream, mut client) = start();
+        let trickle = thread::spawn(move || {
+            let mut record = vec![0x16, 0x03, 0x01, 0x02, 0x00];
+            record.resize(5 + 0x200, 0);
+            for byte in record {
+                if client.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let started = Instant::now();
+        let error = complete_handshake(&mut connection, &mut stream, Instant::now() + Duration::from_millis(300)).unwrap_err();
+        assert!(matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2), "handshake took {:?}", started.elapsed());
+        drop(stream);
+        trickle.join().unwrap();
+    }
+}
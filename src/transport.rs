@@ -0,0 +1,323 @@
+//! Transports the listeners accept clients over: TCP, or Unix domain sockets for clients on the same host.
+//!
+//! A listen address is either a TCP socket address such as `0.0.0.0:33333`, or `unix:` followed by the path of a Unix
+//! domain socket, such as `unix:/run/ctmp/source.sock`. Clients of a Unix domain socket are controlled by the socket's
+//! file permissions rather than the IP access lists.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+
+/// Prefix of a listen address naming a Unix domain socket.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Shortest and longest wait before accepting again after a listener fails to accept a client.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Address a listener accepts clients on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ListenAddr, String> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("missing socket path in '{}'", s)),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(ListenAddr::Tcp).map_err(|_| format!("invalid address '{}', expected host:port or unix:<path>", s)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Function to join listen addresses with commas, for logging.
+pub fn join(addrs: &[ListenAddr]) -> String {
+    addrs.iter().map(ListenAddr::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Permissions given to the socket files of Unix domain socket listeners, as an octal mode such as `660`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketMode(pub u32);
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SocketMode, String> {
+        u32::from_str_radix(s, 8).ok().filter(|mode| *mode <= 0o777).map(SocketMode).ok_or_else(|| format!("invalid socket mode '{}', expected octal permissions such as 660", s))
+    }
+}
+
+impl fmt::Display for SocketMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03o}", self.0)
+    }
+}
+
+/// Address of a connected client.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A client of a Unix domain socket, which has no address of its own, so it is named by the listener's path.
+    Unix(Arc<Path>),
+}
+
+impl Peer {
+    /// The client's IP address, or `None` for a client of a Unix domain socket.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A listener on either transport.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The socket file is removed when the listener is dropped.
+    Unix { listener: UnixListener, path: Arc<Path> },
+}
+
+impl Listener {
+    /// Binds a listener. The socket file of a Unix domain socket is given the permissions in `mode`.
+    pub fn bind(addr: &ListenAddr, mode: SocketMode) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path, mode.0)?;
+                Ok(Listener::Unix { listener, path: Arc::from(path.as_path()) })
+            }
+        }
+    }
+
+    /// Waits for the next client to connect.
+    pub fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, addr)| (Stream::Tcp(stream), Peer::Tcp(addr))),
+            Listener::Unix { listener, path } => listener.accept().map(|(stream, _)| (Stream::Unix(stream), Peer::Unix(Arc::clone(path)))),
+        }
+    }
+
+    /// The address the listener is bound to, with the port chosen by the operating system if port 0 was given.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix { path, .. } => Ok(ListenAddr::Unix(path.to_path_buf())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Function to bind a Unix domain socket whose file has the permissions in `mode`. A socket file left behind by a
+/// process that is no longer running is replaced, but an error is returned if another process is accepting
+/// connections on it.
+pub fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("socket {} is in use by another process", path.display())));
+    }
+
+    // Binding creates the socket file with permissions set by the umask, so it could be connected to before its
+    // permissions are changed. Instead it is bound inside a directory only the relay's user can enter, given its
+    // permissions there, and then moved into place, which also replaces a socket file left behind.
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("socket path {} has no file name", path.display())))?;
+    let dir = path.with_file_name(format!(".{}.{}.tmp", file_name.to_string_lossy(), std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let temp = dir.join("s");
+    let bound = UnixListener::bind(&temp).and_then(|listener| {
+        fs::set_permissions(&temp, fs::Permissions::from_mode(mode))?;
+        fs::rename(&temp, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+/// Wait before accepting again after a listener fails to accept a client. Failures such as running out of file
+/// descriptors (EMFILE) last until clients disconnect, so accepting again straight away would spin on the error. The
+/// wait doubles with each failure in a row, up to a second, and is reset by a successful accept.
+pub struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    pub fn new() -> AcceptBackoff {
+        AcceptBackoff { delay: Duration::ZERO }
+    }
+
+    pub fn succeeded(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    /// Logs a failed accept on the named listener, returning how long to wait before accepting again.
+    pub fn failed(&mut self, error: &io::Error, listener: &str) -> Duration {
+        self.delay = (self.delay * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+        warn!(error = %error, listener, retry_ms = self.delay.as_millis() as u64, "failed to accept client");
+        self.delay
+    }
+}
+
+/// A connection accepted on either transport.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn binds_socket_with_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        for socket_mode in [0o600, 0o660] {
+            let listener = bind_unix(&path, socket_mode).unwrap();
+            assert_eq!(mode(&path), socket_mode);
+            UnixStream::connect(&path).unwrap();
+            listener.accept().unwrap();
+            drop(listener);
+            fs::remove_file(&path).unwrap();
+        }
+        // The private directory the socket was bound in is removed.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        // A socket file whose listener has gone, as left behind by a process that crashed.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(UnixStream::connect(&path).is_err());
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        UnixStream::connect(&path).unwrap();
+        listener.accept().unwrap();
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn accept_backoff_doubles_until_reset() {
+        let error = io::Error::from_raw_os_error(24); // EMFILE
+        let mut backoff = AcceptBackoff::new();
+        let delays: Vec<u64> = (0..9).map(|_| backoff.failed(&error, "test").as_millis() as u64).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+        backoff.succeeded();
+        assert_eq!(backoff.failed(&error, "test"), MIN_ACCEPT_BACKOFF);
+    }
+
+    #[test]
+    fn refuses_socket_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        let _listener = bind_unix(&path, 0o600).unwrap();
+        let error = bind_unix(&path, 0o600).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
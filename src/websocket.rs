@@ -0,0 +1,558 @@
+//! WebSocket gateway for destination clients that cannot open a raw TCP connection, such as dashboards running in a
+//! browser.
+//!
+//! A WebSocket client connects to a `ws_addr` listener (over TLS if the destination listener has TLS enabled) and
+//! chooses what it receives with the query string of its URL, using the same settings as a destination's hello line:
+//!
+//! ```text
+//! ws://relay:8080/?channels=prices,trades&filter=!sensitive&history=100&format=json
+//! ```
+//!
+//! Values are percent-decoded, so characters such as `>` may be escaped. `format` picks how each message is sent:
+//!
+//! - `binary`: one binary WebSocket message holding the complete CTMP message, exactly as TCP destinations receive it.
+//! - `json`: one text WebSocket message holding a JSON object with the decoded header and the base64 encoded payload:
+//!   `{"version":2,"options":0,"sensitive":false,"length":5,"checksum":1234,"message_type":7,"payload":"aGVsbG8="}`.
+//!   `message_type` is `null` for version 1 messages.
+//!
+//! The relay only sends messages; data messages sent by the client are ignored. It answers pings with pongs, and a
+//! close frame by closing the connection after echoing it. Frames from the client must be masked and at most
+//! `MAX_CLIENT_FRAME_LEN` long, or the relay closes the connection. On shutdown the relay sends a close frame after
+//! the client's queued messages.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use ctmp::{CtmpMessage, Version};
+
+use crate::connection::Connection;
+use crate::subscription::Subscription;
+
+/// Maximum length of a client's HTTP upgrade request, so a client cannot make the relay buffer an endless request.
+const MAX_REQUEST_LEN: u64 = 8192;
+
+/// How long a client has to send its upgrade request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Appended to the client's key to compute the `Sec-WebSocket-Accept` header, as defined by RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Close frame with status 1001 (going away), sent when the relay disconnects a client.
+pub const CLOSE_FRAME: [u8; 4] = [0x88, 0x02, 0x03, 0xE9];
+
+/// First byte of a final, unfragmented text or binary frame.
+const TEXT_FRAME: u8 = 0x81;
+const BINARY_FRAME: u8 = 0x82;
+
+/// First byte of a close frame and a pong frame.
+const CLOSE: u8 = 0x88;
+const PONG: u8 = 0x8A;
+
+/// Longest frame accepted from a client, including its header. Clients have nothing to send but control frames, whose
+/// payload is at most 125 bytes, so this leaves room for small data messages, which are ignored.
+const MAX_CLIENT_FRAME_LEN: usize = 4096;
+
+/// Close status codes sent when a client breaks the protocol or sends a frame that is too long.
+const STATUS_PROTOCOL_ERROR: u16 = 1002;
+const STATUS_TOO_BIG: u16 = 1009;
+
+/// How messages are sent to a WebSocket client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsFormat {
+    /// The complete CTMP message, as a binary message.
+    Binary,
+    /// The decoded header and base64 encoded payload, as a JSON text message.
+    Json,
+}
+
+impl FromStr for WsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WsFormat, String> {
+        match s {
+            "binary" => Ok(WsFormat::Binary),
+            "json" => Ok(WsFormat::Json),
+            _ => Err(format!("unknown WebSocket format '{}', expected binary or json", s)),
+        }
+    }
+}
+
+impl fmt::Display for WsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WsFormat::Binary => "binary",
+            WsFormat::Json => "json",
+        })
+    }
+}
+
+/// Function to perform the WebSocket upgrade with a newly connected client, returning what it subscribed to and the
+/// format it asked for. Returns an error if the request was not a valid upgrade, after telling the client why with an
+/// HTTP error response.
+pub fn handshake(connection: &mut Connection, relay_versions: &[Version], default_format: WsFormat) -> io::Result<(Subscription, WsFormat)> {
+    connection.stream().set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let request = read_request(&mut *connection);
+    connection.stream().set_read_timeout(None)?;
+    let request = request?;
+
+    match upgrade(&request, relay_versions, default_format) {
+        Ok((subscription, format)) => {
+            write!(
+                connection,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(request.key.as_deref().unwrap_or_default())
+            )?;
+            connection.flush()?;
+            Ok((subscription, format))
+        }
+        Err((status, reason)) => {
+            // Clients speaking another WebSocket version are told the one the relay supports.
+            let extra = if status.starts_with("426") { "Sec-WebSocket-Version: 13\r\n" } else { "" };
+            let _ = write!(
+                connection,
+                "HTTP/1.1 {}\r\n{}Content-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+                status,
+                extra,
+                reason.len() + 1,
+                reason
+            );
+            let _ = connection.flush();
+            Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+    }
+}
+
+/// The parts of a client's HTTP request that matter for the upgrade.
+struct Request {
+    method: String,
+    /// Query string of the requested URL, without the '?'.
+    query: String,
+    upgrade: bool,
+    key: Option<String>,
+    version: Option<String>,
+}
+
+/// Function to read a client's HTTP request line and headers.
+fn read_request(stream: impl Read) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LEN));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let query = target.split_once('?').map(|(_, query)| query.to_string()).unwrap_or_default();
+
+    let mut request = Request { method, query, upgrade: false, key: None, version: None };
+    let mut connection_upgrade = false;
+    let mut upgrade_websocket = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP request is incomplete or too long"));
+        }
+        if line.trim().is_empty() {
+            break;
+        }
+
+        // Header names are case insensitive, and Connection may list several options.
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "connection" => connection_upgrade = value.split(',').any(|option| option.trim().eq_ignore_ascii_case("upgrade")),
+            "upgrade" => upgrade_websocket = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => request.key = Some(value.to_string()),
+            "sec-websocket-version" => request.version = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    request.upgrade = connection_upgrade && upgrade_websocket;
+    Ok(request)
+}
+
+/// Function to check an upgrade request and parse the settings in its query string. Returns the HTTP status and
+/// reason to reply with if the request is refused.
+fn upgrade(request: &Request, relay_versions: &[Version], default_format: WsFormat) -> Result<(Subscription, WsFormat), (&'static str, String)> {
+    if request.method != "GET" {
+        return Err(("405 Method Not Allowed", "expected a GET request".to_string()));
+    }
+    if !request.upgrade || request.key.is_none() {
+        return Err(("426 Upgrade Required", "expected a WebSocket upgrade request".to_string()));
+    }
+    if request.version.as_deref() != Some("13") {
+        return Err(("426 Upgrade Required", "unsupported WebSocket version, expected 13".to_string()));
+    }
+
+    // The query string is turned into a hello line, so it is parsed just like a TCP destination's hello.
+    let mut format = default_format;
+    let mut hello = String::from("CTMP");
+    for pair in request.query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let (key, value) = (percent_decode(key), percent_decode(value));
+        let (key, value) = match (key, value) {
+            (Some(key), Some(value)) if !key.contains(char::is_whitespace) && !value.contains(char::is_whitespace) => (key, value),
+            _ => return Err(("400 Bad Request", format!("invalid query parameter '{}'", pair))),
+        };
+        if key == "format" {
+            format = value.parse().map_err(|reason| ("400 Bad Request", reason))?;
+        } else {
+            hello.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    let subscription = Subscription::from_hello(&hello, relay_versions).map_err(|reason| ("400 Bad Request", reason))?;
+    Ok((subscription, format))
+}
+
+/// Function to decode %XX escapes in a query string parameter. Returns `None` if an escape is invalid or the result
+/// is not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            // Both digits are checked, as `from_str_radix` would also accept a sign such as in `%+1`.
+            let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Function to compute the `Sec-WebSocket-Accept` header from the client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    base64(digest.as_ref())
+}
+
+/// Function to encode a message as a WebSocket frame in the given format, replacing the contents of `frame`. Frames
+/// sent by a server are not masked.
+pub fn encode(message: &CtmpMessage, format: WsFormat, frame: &mut Vec<u8>) {
+    let json;
+    let (first, body) = match format {
+        WsFormat::Binary => (BINARY_FRAME, message.as_bytes()),
+        WsFormat::Json => {
+            json = to_json(message);
+            (TEXT_FRAME, json.as_bytes())
+        }
+    };
+
+    frame.clear();
+    frame.push(first);
+    match body.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(body);
+}
+
+/// A frame received from a client.
+#[derive(Debug, PartialEq, Eq)]
+enum ClientFrame {
+    /// A ping, to be answered with a pong carrying the same payload.
+    Ping(Vec<u8>),
+    /// A close frame, with the status code given by the client, if any.
+    Close(Option<u16>),
+    /// A data message, continuation or pong, which is ignored.
+    Ignored,
+}
+
+/// Function to parse the frame at the start of the bytes received from a client. Returns the frame and its length,
+/// `None` if more bytes are needed, or the close status to send if the frame breaks the protocol.
+fn parse_client_frame(bytes: &[u8]) -> Result<Option<(ClientFrame, usize)>, u16> {
+    let [first, second, ..] = *bytes else {
+        return Ok(None);
+    };
+    let opcode = first & 0x0F;
+    let control = opcode & 0x08 != 0;
+    if first & 0x70 != 0 || !matches!(opcode, 0x0..=0x2 | 0x8..=0xA) {
+        return Err(STATUS_PROTOCOL_ERROR); // Reserved bits or opcodes, as no extensions are negotiated.
+    }
+    if second & 0x80 == 0 {
+        return Err(STATUS_PROTOCOL_ERROR); // Every frame sent by a client must be masked.
+    }
+
+    let (payload_len, header_len) = match second & 0x7F {
+        126 if bytes.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([bytes[2], bytes[3]]) as u64, 4),
+        127 if bytes.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(bytes[2..10].try_into().expect("length is 8 bytes")), 10),
+        len => (len as u64, 2),
+    };
+    if control && (first & 0x80 == 0 || payload_len > 125) {
+        return Err(STATUS_PROTOCOL_ERROR); // Control frames must not be fragmented or long.
+    }
+    let frame_len = header_len as u64 + 4 + payload_len;
+    if frame_len > MAX_CLIENT_FRAME_LEN as u64 {
+        return Err(STATUS_TOO_BIG);
+    }
+    let frame_len = frame_len as usize;
+    if bytes.len() < frame_len {
+        return Ok(None);
+    }
+
+    let mask = &bytes[header_len..header_len + 4];
+    let payload: Vec<u8> = bytes[header_len + 4..frame_len].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    let frame = match opcode {
+        0x8 if payload.len() == 1 => return Err(STATUS_PROTOCOL_ERROR),
+        0x8 => ClientFrame::Close(payload.get(..2).map(|status| u16::from_be_bytes([status[0], status[1]]))),
+        0x9 => ClientFrame::Ping(payload),
+        _ => ClientFrame::Ignored,
+    };
+    Ok(Some((frame, frame_len)))
+}
+
+/// Reads the frames sent by a WebSocket client, answering pings and close frames.
+pub struct ClientReader {
+    buffer: Vec<u8>,
+}
+
+impl ClientReader {
+    pub fn new() -> ClientReader {
+        ClientReader { buffer: Vec::new() }
+    }
+
+    /// Reads whatever the client has sent without waiting for more, and answers it. Returns true once the connection
+    /// is closing: the client sent a close frame, which has been echoed, broke the protocol, which it has been sent a
+    /// close frame for, or disconnected.
+    pub fn poll(&mut self, connection: &mut Connection) -> io::Result<bool> {
+        connection.stream().set_nonblocking(true)?;
+        let read = self.read_available(connection);
+        connection.stream().set_nonblocking(false)?;
+        let disconnected = read?;
+
+        loop {
+            match parse_client_frame(&self.buffer) {
+                Ok(None) => break,
+                Ok(Some((frame, len))) => {
+                    self.buffer.drain(..len);
+                    match frame {
+                        ClientFrame::Ping(payload) => {
+                            // Control frame payloads are at most 125 bytes, so the length fits in the second byte.
+                            let mut pong = vec![PONG, payload.len() as u8];
+                            pong.extend_from_slice(&payload);
+                            connection.write_all(&pong)?;
+                        }
+                        ClientFrame::Close(status) => {
+                            connection.write_all(&close_frame(status))?;
+                            return Ok(true);
+                        }
+                        ClientFrame::Ignored => {}
+                    }
+                }
+                Err(status) => {
+                    connection.write_all(&close_frame(Some(status)))?;
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(disconnected)
+    }
+
+    /// Function to read what is available from a non-blocking connection, returning true if the client disconnected.
+    fn read_available(&mut self, connection: &mut Connection) -> io::Result<bool> {
+        let mut chunk = [0u8; 1024];
+        // Reading stops once a whole frame of the maximum length is buffered, as anything longer is refused anyway.
+        while self.buffer.len() < MAX_CLIENT_FRAME_LEN {
+            match connection.read(&mut chunk) {
+                Ok(0) => return Ok(true),
+                Ok(len) => self.buffer.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Function to build a close frame with an optional status code.
+fn close_frame(status: Option<u16>) -> Vec<u8> {
+    match status {
+        Some(status) => [CLOSE, 2, (status >> 8) as u8, status as u8].to_vec(),
+        None => vec![CLOSE, 0],
+    }
+}
+
+/// Function to describe a message as a JSON object. Every field is a number, a boolean or base64, so nothing needs
+/// escaping.
+fn to_json(message: &CtmpMessage) -> String {
+    let header = message.header();
+    let message_type = header.message_type().map_or_else(|| "null".to_string(), |message_type| message_type.to_string());
+    format!(
+        "{{\"version\":{},\"options\":{},\"sensitive\":{},\"length\":{},\"checksum\":{},\"message_type\":{},\"payload\":\"{}\"}}",
+        header.version(),
+        header.options,
+        header.is_sensitive(),
+        header.length,
+        header.checksum,
+        message_type,
+        base64(message.payload())
+    )
+}
+
+/// Function to encode bytes as standard base64, with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = u32::from(chunk[0]) << 16 | u32::from(*chunk.get(1).unwrap_or(&0)) << 8 | u32::from(*chunk.get(2).unwrap_or(&0));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ctmp::{Encoder, HEADER_LEN};
+
+    /// Function to mask a client frame with a fixed key, as clients must.
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_sample() {
+        // The example from RFC 6455 section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xFF, 0xFE, 0xFD]), "//79");
+    }
+
+    #[test]
+    fn percent_decode_accepts_escapes() {
+        assert_eq!(percent_decode("a%2Cb%2c"), Some("a,b,".to_string()));
+        assert_eq!(percent_decode("caf%C3%A9"), Some("café".to_string()));
+        assert_eq!(percent_decode(""), Some(String::new()));
+    }
+
+    #[test]
+    fn percent_decode_rejects_invalid_input() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("a%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%-1"), None);
+        // Escapes that do not decode to UTF-8.
+        assert_eq!(percent_decode("%FF"), None);
+        assert_eq!(percent_decode("%C3"), None);
+    }
+
+    #[test]
+    fn encode_uses_shortest_length_field() {
+        // Binary frames carry the whole message, header included, so the payload is sized to make the body a given length.
+        for (body_len, header) in [
+            (125, vec![BINARY_FRAME, 125]),
+            (126, vec![BINARY_FRAME, 126, 0x00, 0x7E]),
+            (0xFFFF, vec![BINARY_FRAME, 126, 0xFF, 0xFF]),
+            (0x10000, vec![BINARY_FRAME, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]),
+        ] {
+            let message = Encoder::new().encode(&vec![0xAB; body_len - HEADER_LEN]).unwrap();
+            let mut frame = Vec::new();
+            encode(&message, WsFormat::Binary, &mut frame);
+            assert_eq!(&frame[..header.len()], header, "body of {} bytes", body_len);
+            assert_eq!(&frame[header.len()..], message.as_bytes());
+        }
+    }
+
+    #[test]
+    fn encode_json_as_text_frame() {
+        let message = Encoder::new().encode(b"hi").unwrap();
+        let mut frame = Vec::new();
+        encode(&message, WsFormat::Json, &mut frame);
+        assert_eq!(frame[0], TEXT_FRAME);
+        let json = std::str::from_utf8(&frame[2..]).unwrap();
+        assert_eq!(frame[1] as usize, json.len());
+        assert!(json.contains("\"payload\":\"aGk=\""), "{}", json);
+    }
+
+    #[test]
+    fn parses_masked_control_frames() {
+        let ping = client_frame(0x89, b"are you there");
+        assert_eq!(parse_client_frame(&ping), Ok(Some((ClientFrame::Ping(b"are you there".to_vec()), ping.len()))));
+
+        let close = client_frame(0x88, &1000u16.to_be_bytes());
+        assert_eq!(parse_client_frame(&close), Ok(Some((ClientFrame::Close(Some(1000)), close.len()))));
+        let close = client_frame(0x88, b"");
+        assert_eq!(parse_client_frame(&close), Ok(Some((ClientFrame::Close(None), close.len()))));
+
+        let text = client_frame(0x81, &[b'x'; 200]);
+        assert_eq!(parse_client_frame(&text), Ok(Some((ClientFrame::Ignored, text.len()))));
+    }
+
+    #[test]
+    fn waits_for_whole_frame() {
+        let ping = client_frame(0x89, b"ping");
+        for len in 0..ping.len() {
+            assert_eq!(parse_client_frame(&ping[..len]), Ok(None), "{} bytes", len);
+        }
+        // Only the first frame is parsed when more follow.
+        let two = [ping.clone(), ping.clone()].concat();
+        assert_eq!(parse_client_frame(&two), Ok(Some((ClientFrame::Ping(b"ping".to_vec()), ping.len()))));
+    }
+
+    #[test]
+    fn rejects_protocol_errors() {
+        // Unmasked frames.
+        assert_eq!(parse_client_frame(&[0x89, 0x00]), Err(STATUS_PROTOCOL_ERROR));
+        // Reserved bits and opcodes.
+        assert_eq!(parse_client_frame(&client_frame(0xC1, b"")), Err(STATUS_PROTOCOL_ERROR));
+        assert_eq!(parse_client_frame(&client_frame(0x83, b"")), Err(STATUS_PROTOCOL_ERROR));
+        // Fragmented or long control frames.
+        assert_eq!(parse_client_frame(&client_frame(0x09, b"")), Err(STATUS_PROTOCOL_ERROR));
+        assert_eq!(parse_client_frame(&client_frame(0x89, &[0; 126])), Err(STATUS_PROTOCOL_ERROR));
+        // A close frame whose status code is cut short.
+        assert_eq!(parse_client_frame(&client_frame(0x88, &[0x03])), Err(STATUS_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_frames_over_limit() {
+        let payload_limit = MAX_CLIENT_FRAME_LEN - 8;
+        let frame = client_frame(0x82, &vec![0; payload_limit]);
+        assert_eq!(frame.len(), MAX_CLIENT_FRAME_LEN);
+        assert!(matches!(parse_client_frame(&frame), Ok(Some((ClientFrame::Ignored, _)))));
+        // The length is checked from the header alone, before the payload arrives.
+        let frame = client_frame(0x82, &vec![0; payload_limit + 1]);
+        assert_eq!(parse_client_frame(&frame[..8]), Err(STATUS_TOO_BIG));
+    }
+}
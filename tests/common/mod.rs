@@ -0,0 +1,102 @@
+//! Helpers shared by the integration tests, which run the relay binary on ephemeral ports and talk to it over TCP.
+
+#![allow(dead_code)]
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ctmp::{Encoder, OPTION_SENSITIVE};
+
+/// How long a test waits for the relay to start, or for data to arrive, before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A relay process, killed when dropped.
+pub struct Relay {
+    child: Child,
+    pub source_addr: SocketAddr,
+    pub dest_addr: SocketAddr,
+}
+
+impl Relay {
+    /// Starts the relay with the given extra arguments, listening for sources and destinations on free ports.
+    pub fn start(args: &[&str]) -> Relay {
+        let source_addr = free_addr();
+        let dest_addr = free_addr();
+        let child = Command::new(env!("CARGO_BIN_EXE_tcp-server"))
+            .arg("--source-addr")
+            .arg(source_addr.to_string())
+            .arg("--dest-addr")
+            .arg(dest_addr.to_string())
+            .args(["--log-level", "warn"])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("could not start the relay");
+        let mut relay = Relay { child, source_addr, dest_addr };
+
+        // The destination listener is bound after the source listener, so once it accepts both are ready.
+        let started = Instant::now();
+        while TcpStream::connect(dest_addr).is_err() {
+            if let Some(status) = relay.child.try_wait().unwrap() {
+                panic!("relay exited during startup with {}", status);
+            }
+            assert!(started.elapsed() < TIMEOUT, "relay did not start listening");
+            thread::sleep(Duration::from_millis(20));
+        }
+        relay
+    }
+
+    /// Connects a source client.
+    pub fn source(&self) -> TcpStream {
+        TcpStream::connect(self.source_addr).unwrap()
+    }
+
+    /// Connects a destination client, with a read timeout so a missing message fails the test instead of hanging it.
+    pub fn destination(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.dest_addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Function to find a free port on the loopback interface. The port is released again before the relay binds it,
+/// which is racy in theory but fine for tests.
+pub fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Function to encode a version 1 CTMP message, with a valid checksum if it is sensitive.
+pub fn frame(payload: &[u8], sensitive: bool) -> Vec<u8> {
+    let options = if sensitive { OPTION_SENSITIVE } else { 0 };
+    Encoder::new().options(options).encode(payload).unwrap().into_bytes()
+}
+
+/// Function to read a line sent by the relay, such as the reply to a hello, without its newline.
+pub fn read_line(stream: &mut impl Read) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while byte[0] != b'\n' {
+        stream.read_exact(&mut byte).expect("did not receive a complete line");
+        line.push(byte[0]);
+    }
+    line.pop();
+    String::from_utf8(line).unwrap()
+}
+
+/// Function to read exactly `len` bytes, failing the test if they do not arrive in time.
+pub fn read_len(stream: &mut impl Read, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    stream.read_exact(&mut bytes).expect("did not receive the expected bytes");
+    bytes
+}
@@ -0,0 +1,71 @@
+//! Messages relayed while the relay is still waiting for a destination's hello must be delivered, not lost.
+
+mod common;
+
+use std::io::Write;
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use common::{Relay, frame, read_len, read_line};
+
+/// Function to send a message straight after a destination connects without a hello, while the relay is still waiting
+/// for one, and check the destination receives it.
+fn message_during_hello(args: &[&str]) {
+    let relay = Relay::start(&[&["--dest-hello-timeout-ms", "1000"], args].concat());
+    let mut destination = relay.destination();
+    // Give the relay time to accept the destination, but not for the hello timeout to pass.
+    thread::sleep(Duration::from_millis(200));
+
+    let message = frame(b"sent during the hello", false);
+    relay.source().write_all(&message).unwrap();
+    assert_eq!(read_len(&mut destination, message.len()), message);
+}
+
+#[test]
+fn destination_receives_message_sent_during_hello() {
+    message_during_hello(&[]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn destination_receives_message_sent_during_hello_async() {
+    message_during_hello(&["--runtime", "async"]);
+}
+
+/// Function to relay more messages than fit in a destination's queue before it sends its hello, and check it receives
+/// the ones kept by the overflow policy and then live traffic.
+fn overflow_during_hello(policy: &str, kept: Range<usize>) {
+    let relay = Relay::start(&["--dest-queue-depth", "4", "--dest-overflow", policy, "--dest-hello-timeout-ms", "2000"]);
+    let messages: Vec<Vec<u8>> = (0..7).map(|i| frame(format!("message {}", i).as_bytes(), false)).collect();
+    let mut destination = relay.destination();
+    thread::sleep(Duration::from_millis(200));
+
+    let mut source = relay.source();
+    for message in &messages[..6] {
+        source.write_all(message).unwrap();
+    }
+    thread::sleep(Duration::from_millis(200));
+    destination.write_all(b"CTMP channels=default\n").unwrap();
+    assert!(read_line(&mut destination).starts_with("CTMP OK"));
+
+    let expected = messages[kept].concat();
+    assert_eq!(read_len(&mut destination, expected.len()), expected, "policy {}", policy);
+    source.write_all(&messages[6]).unwrap();
+    assert_eq!(read_len(&mut destination, messages[6].len()), messages[6], "policy {}", policy);
+}
+
+#[test]
+fn overflow_during_hello_drop_oldest() {
+    overflow_during_hello("drop-oldest", 2..6);
+}
+
+#[test]
+fn overflow_during_hello_drop_newest() {
+    overflow_during_hello("drop-newest", 0..4);
+}
+
+#[test]
+fn overflow_during_hello_disconnect() {
+    overflow_during_hello("disconnect", 2..6);
+}
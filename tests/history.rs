@@ -0,0 +1,51 @@
+//! A new destination is sent the history before live traffic, with no gaps, even when messages are relayed while the
+//! relay waits for its hello and the history already fills its queue.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use common::{Relay, frame, read_len, read_line};
+
+/// Function to fill the history, relay two messages while a destination is connecting and check the destination
+/// receives the newest history, then those messages, then live traffic, with the given overflow policy.
+fn full_history_and_messages_during_hello(policy: &str) {
+    let relay = Relay::start(&["--history-messages", "4", "--dest-queue-depth", "4", "--dest-overflow", policy, "--dest-hello-timeout-ms", "2000"]);
+    let messages: Vec<Vec<u8>> = (0..7).map(|i| frame(format!("message {}", i).as_bytes(), false)).collect();
+    let mut source = relay.source();
+    for message in &messages[..4] {
+        source.write_all(message).unwrap();
+    }
+    thread::sleep(Duration::from_millis(200));
+
+    let mut destination = relay.destination();
+    thread::sleep(Duration::from_millis(200));
+    source.write_all(&messages[4]).unwrap();
+    source.write_all(&messages[5]).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    destination.write_all(b"CTMP history=all\n").unwrap();
+    assert!(read_line(&mut destination).starts_with("CTMP OK"));
+
+    // The history holds four messages, so the two relayed during the hello push the two oldest out of the replay.
+    let expected = messages[2..6].concat();
+    assert_eq!(read_len(&mut destination, expected.len()), expected, "policy {}", policy);
+    source.write_all(&messages[6]).unwrap();
+    assert_eq!(read_len(&mut destination, messages[6].len()), messages[6], "policy {}", policy);
+}
+
+#[test]
+fn full_history_and_messages_during_hello_drop_oldest() {
+    full_history_and_messages_during_hello("drop-oldest");
+}
+
+#[test]
+fn full_history_and_messages_during_hello_drop_newest() {
+    full_history_and_messages_during_hello("drop-newest");
+}
+
+#[test]
+fn full_history_and_messages_during_hello_disconnect() {
+    full_history_and_messages_during_hello("disconnect");
+}
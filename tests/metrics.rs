@@ -0,0 +1,86 @@
+//! The metrics count only accepted frames as received and only destinations that have completed their handshake as
+//! connected.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Relay, TIMEOUT, free_addr, frame, read_len, read_line};
+
+/// Function to scrape the metrics and return the value of a counter or gauge.
+fn metric(addr: SocketAddr, name: &str) -> u64 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+        .unwrap_or_else(|| panic!("{} missing from the metrics:\n{}", name, response))
+}
+
+/// Function to wait for a metric to reach the expected value, as clients are handled on other threads.
+fn wait_for(addr: SocketAddr, name: &str, expected: u64) {
+    let started = Instant::now();
+    loop {
+        let value = metric(addr, name);
+        if value == expected {
+            return;
+        }
+        assert!(started.elapsed() < TIMEOUT, "{} is {}, expected {}", name, value, expected);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Function to check the destination gauge and the frame counters, with the given extra arguments.
+fn counts_handshaken_destinations_and_accepted_frames(args: &[&str]) {
+    let metrics_addr = free_addr();
+    let relay = Relay::start(&[&["--metrics-addr", &metrics_addr.to_string(), "--dest-hello-timeout-ms", "2000"], args].concat());
+
+    // The connection made to check the relay has started sent no hello, so it counts as a destination until a write to
+    // it fails. A destination still sending its hello is not counted.
+    thread::sleep(Duration::from_millis(200));
+    let started = metric(metrics_addr, "ctmp_destinations_connected");
+    let mut destination = relay.destination();
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(metric(metrics_addr, "ctmp_destinations_connected"), started);
+    destination.write_all(b"CTMP\n").unwrap();
+    assert!(read_line(&mut destination).starts_with("CTMP OK"));
+    wait_for(metrics_addr, "ctmp_destinations_connected", started + 1);
+
+    // A sensitive message with a wrong checksum is dropped, and counted as a checksum failure only.
+    let mut bad = frame(b"tampered", true);
+    let last = bad.len() - 1;
+    bad[last] ^= 0xFF;
+    let good = frame(b"valid", false);
+    let mut source = relay.source();
+    source.write_all(&[bad, good.clone()].concat()).unwrap();
+    assert_eq!(read_len(&mut destination, good.len()), good);
+    wait_for(metrics_addr, "ctmp_frames_relayed_total", 1);
+    assert_eq!(metric(metrics_addr, "ctmp_frames_received_total"), 1);
+    assert_eq!(metric(metrics_addr, "ctmp_checksum_failures_total"), 1);
+
+    // The relay notices destinations have gone when writing to them fails.
+    drop(destination);
+    let started = Instant::now();
+    while metric(metrics_addr, "ctmp_destinations_connected") > 0 {
+        assert!(started.elapsed() < TIMEOUT, "disconnected destinations are still counted");
+        source.write_all(&good).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn counts_destinations_and_frames() {
+    counts_handshaken_destinations_and_accepted_frames(&[]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn counts_destinations_and_frames_async() {
+    counts_handshaken_destinations_and_accepted_frames(&["--runtime", "async"]);
+}
@@ -0,0 +1,42 @@
+//! A source whose connection is reset partway through a message must not stop the relay from serving the next source.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use common::{Relay, frame, read_len};
+use socket2::SockRef;
+
+/// Function to reset a source connection after half a message, then check a new source's message is still relayed.
+fn reset_mid_frame(args: &[&str]) {
+    let relay = Relay::start(args);
+    let mut destination = relay.destination();
+    // Let the destination's hello timeout pass, so it is subscribed before anything is relayed.
+    thread::sleep(Duration::from_millis(300));
+
+    let partial = frame(b"never finished", true);
+    let mut source = relay.source();
+    source.write_all(&partial[..partial.len() / 2]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    // A zero linger time makes closing the socket send a reset instead of a normal shutdown.
+    SockRef::from(&source).set_linger(Some(Duration::ZERO)).unwrap();
+    drop(source);
+
+    let message = frame(b"after the reset", false);
+    let mut source = relay.source();
+    source.write_all(&message).unwrap();
+    assert_eq!(read_len(&mut destination, message.len()), message);
+}
+
+#[test]
+fn source_reset_mid_frame() {
+    reset_mid_frame(&[]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn source_reset_mid_frame_async() {
+    reset_mid_frame(&["--runtime", "async"]);
+}
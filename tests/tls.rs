@@ -0,0 +1,50 @@
+//! Messages are relayed from a source to a destination when both connect over TLS.
+//!
+//! `fixtures/relay.crt` is a certificate for `localhost` signed by the test CA in `fixtures/ca.crt`, and
+//! `fixtures/relay.key` its private key. They are only used by this test.
+
+mod common;
+
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use common::{Relay, TIMEOUT, frame, read_len};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+/// Function to connect to the relay over TLS, trusting only the test CA.
+fn connect(addr: SocketAddr) -> StreamOwned<ClientConnection, TcpStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(format!("{}/ca.crt", FIXTURES)).unwrap()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut connection = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream).expect("TLS handshake with the relay failed");
+    }
+    StreamOwned::new(connection, stream)
+}
+
+#[test]
+fn relays_message_over_tls() {
+    let cert = format!("{}/relay.crt", FIXTURES);
+    let key = format!("{}/relay.key", FIXTURES);
+    let relay = Relay::start(&["--source-tls-cert", &cert, "--source-tls-key", &key, "--dest-tls-cert", &cert, "--dest-tls-key", &key]);
+
+    let mut destination = connect(relay.dest_addr);
+    let mut source = connect(relay.source_addr);
+    let message = frame(b"over tls", true);
+    source.write_all(&message).unwrap();
+    source.flush().unwrap();
+    assert_eq!(read_len(&mut destination, message.len()), message);
+}
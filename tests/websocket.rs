@@ -0,0 +1,116 @@
+//! Destinations connecting over WebSocket receive messages in the format they ask for, and the relay answers their
+//! pings and close frames.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Relay, TIMEOUT, free_addr, frame, read_len, read_line};
+
+/// Function to connect to the WebSocket listener and upgrade the connection. The listener is bound after the
+/// destination listener, so connecting is retried until it accepts.
+fn upgrade(addr: SocketAddr, target: &str) -> TcpStream {
+    let started = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(e) => assert!(started.elapsed() < TIMEOUT, "could not connect to the WebSocket listener: {}", e),
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        target
+    )
+    .unwrap();
+
+    assert_eq!(read_line(&mut stream), "HTTP/1.1 101 Switching Protocols\r");
+    let mut accept = None;
+    loop {
+        let line = read_line(&mut stream);
+        if line == "\r" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Sec-WebSocket-Accept: ") {
+            accept = Some(value.trim_end().to_string());
+        }
+    }
+    // The accept value for this key is given in RFC 6455 section 1.3.
+    assert_eq!(accept.as_deref(), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    stream
+}
+
+/// Function to read a frame sent by the relay, which is never masked, returning its first byte and its payload.
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = read_len(stream, 2);
+    let len = match header[1] {
+        126 => u16::from_be_bytes(read_len(stream, 2).try_into().unwrap()) as usize,
+        127 => u64::from_be_bytes(read_len(stream, 8).try_into().unwrap()) as usize,
+        len => len as usize,
+    };
+    (header[0], read_len(stream, len))
+}
+
+/// Function to send a masked control frame, as a client must.
+fn send_control(stream: &mut TcpStream, first: u8, payload: &[u8]) {
+    let mask = [0xA1, 0xB2, 0xC3, 0xD4];
+    let mut frame = vec![first, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+#[test]
+fn relays_binary_and_json_messages() {
+    let ws_addr = free_addr();
+    let relay = Relay::start(&["--ws-addr", &ws_addr.to_string()]);
+    let mut binary = upgrade(ws_addr, "/");
+    let mut json = upgrade(ws_addr, "/?format=json");
+    // Give the relay time to register both clients.
+    thread::sleep(Duration::from_millis(200));
+
+    let message = frame(b"hello", false);
+    relay.source().write_all(&message).unwrap();
+
+    assert_eq!(read_frame(&mut binary), (0x82, message));
+    let (first, text) = read_frame(&mut json);
+    assert_eq!(first, 0x81);
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with('{') && text.ends_with('}'), "{}", text);
+    assert!(text.contains("\"length\":5"), "{}", text);
+    // The payload is base64 encoded.
+    assert!(text.contains("\"payload\":\"aGVsbG8=\""), "{}", text);
+}
+
+#[test]
+fn answers_ping_and_close() {
+    let ws_addr = free_addr();
+    let _relay = Relay::start(&["--ws-addr", &ws_addr.to_string()]);
+    let mut client = upgrade(ws_addr, "/");
+
+    send_control(&mut client, 0x89, b"are you there");
+    assert_eq!(read_frame(&mut client), (0x8A, b"are you there".to_vec()));
+
+    // The close frame is echoed with the client's status code, then the relay closes the connection.
+    send_control(&mut client, 0x88, &1000u16.to_be_bytes());
+    assert_eq!(read_frame(&mut client), (0x88, 1000u16.to_be_bytes().to_vec()));
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "relay sent {:?} after the close frame", rest);
+}
+
+#[test]
+fn closes_on_unmasked_frame() {
+    let ws_addr = free_addr();
+    let _relay = Relay::start(&["--ws-addr", &ws_addr.to_string()]);
+    let mut client = upgrade(ws_addr, "/");
+
+    client.write_all(&[0x89, 0x00]).unwrap();
+    assert_eq!(read_frame(&mut client), (0x88, 1002u16.to_be_bytes().to_vec()));
+}